@@ -0,0 +1,1677 @@
+use crate::errors;
+use std::collections::HashMap;
+use std::ops::Range;
+
+/// The number of times a single exploding die may explode before it stops rolling.
+pub const DEFAULT_EXPLOSION_LIMIT: u32 = 100;
+/// The number of times a single die may be rerolled before giving up with an error.
+pub const DEFAULT_REROLL_LIMIT: u32 = 100;
+/// The most outcomes the statistics of a single die may work through before giving up with an error.
+pub const DEFAULT_OUTCOME_LIMIT: u64 = 1_000_000;
+/// How the result of a division is rounded to a whole number.
+///
+/// An `Equation` uses [`RoundingPolicy::Truncate`] unless `Equation::with_rounding` chooses another policy.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum RoundingPolicy {
+    /// drops the fraction, rounding towards zero
+    Truncate,
+    /// rounds down, so -7/2 gives -4
+    Floor,
+    /// rounds up, so 7/2 gives 4
+    Ceil,
+    /// rounds to the nearest whole number, with halves rounded away from zero
+    HalfUp,
+}
+/// Limits on how much work an equation may ask for, so equations from untrusted users can't tie up a server.
+///
+/// `Equation::new` uses `Limits::default()`, which only limits explosions, rerolls and the outcomes worked through
+/// for the statistics of a die, while the functions in
+/// `roll` such as `roll::roll()` use the much smaller [`Limits::untrusted`]. `Equation::new_with_limits` compiles
+/// an equation with other limits.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct Limits {
+    /// the most dice that can be rolled by a single roll of the equation, not counting explosions or rerolls
+    pub max_dice: u64,
+    /// the most sides a die can have
+    pub max_sides: u32,
+    /// the longest the equation can be in bytes
+    pub max_length: usize,
+    /// how deeply parentheses can be nested
+    pub max_depth: u32,
+    /// the most times a repeat group such as "6x(4d6)" can be repeated
+    pub max_repeats: u32,
+    /// how many times a single die can explode before it stops rolling
+    pub max_explosions: u32,
+    /// how many times a single die can be rerolled before giving up with an error
+    pub max_rerolls: u32,
+    /// the most outcomes low(), high(), average() and the like can work through to find the chance of every
+    /// total of a die that is kept, dropped or compounded into successes, which is about its sides times the
+    /// number of explosions followed
+    pub max_outcomes: u64,
+}
+impl Limits {
+    /// Conservative limits for equations typed by users, such as in a chat bot on a shared server.
+    ///
+    /// These allow 1000 dice, 1000 sides, 500 bytes, 32 levels of parentheses, 100 repeats, 100
+    /// explosions or rerolls of a single die and 1000 outcomes for the statistics of a single die, which is far
+    /// more than any game needs.
+    pub const fn untrusted() -> Limits {
+        Limits {
+            max_dice: 1000,
+            max_sides: 1000,
+            max_length: 500,
+            max_depth: 32,
+            max_repeats: 100,
+            max_explosions: 100,
+            max_rerolls: 100,
+            max_outcomes: 1000,
+        }
+    }
+}
+impl Default for Limits {
+    /// No limits other than [`DEFAULT_EXPLOSION_LIMIT`], [`DEFAULT_REROLL_LIMIT`] and [`DEFAULT_OUTCOME_LIMIT`].
+    fn default() -> Limits {
+        Limits {
+            max_dice: u64::MAX,
+            max_sides: u32::MAX,
+            max_length: usize::MAX,
+            max_depth: u32::MAX,
+            max_repeats: u32::MAX,
+            max_explosions: DEFAULT_EXPLOSION_LIMIT,
+            max_rerolls: DEFAULT_REROLL_LIMIT,
+            max_outcomes: DEFAULT_OUTCOME_LIMIT,
+        }
+    }
+}
+
+/// How equations are written, for reading notation other than "3d6 * 2".
+///
+/// `Equation::new` and the functions in `roll` use `Dialect::default()`, which reads upper
+/// case letters such as the `D` of "3D6" as lower case and accepts `×`, `÷` and `−` as operators. Variable
+/// names and labels are always kept as they were written. `Equation::new_with_dialect` compiles an equation
+/// written in another dialect.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct Dialect {
+    /// letters that roll dice the same as `d`, such as the `W` of the German "3W6", they can't be letters
+    /// already used by the notation such as the `k` of "4d6kh3" or the `m` of "max(..)"
+    pub die_letters: Vec<char>,
+    /// accepts `×`, `÷` and `−` for `*`, `/` and `-`, as found in text pasted from documents
+    pub unicode_operators: bool,
+    /// when false, upper case letters are read as lower case apart from the `F` of Fate dice such as "4dF"
+    pub case_sensitive: bool,
+}
+impl Dialect {
+    /// German dice notation, where dice are written with a `W` for "Würfel" as in "3W6".
+    pub fn german() -> Dialect {
+        Dialect {
+            die_letters: vec!['w'],
+            ..Dialect::default()
+        }
+    }
+    /// Checks that none of the die letters are already used by the notation, which would be read as dice.
+    fn check(&self) -> Result<(), errors::ParseError> {
+        // the letters of the die modifiers, the function names and the `F` of Fate dice
+        const NOTATION: &[char] = &[
+            'a', 'b', 'c', 'd', 'e', 'f', 'h', 'i', 'k', 'l', 'm', 'n', 'o', 'p', 'r', 's', 'u',
+            'x', 'F',
+        ];
+        let used = |letter: &&char| match self.case_sensitive {
+            true => NOTATION.contains(letter),
+            false => NOTATION.iter().any(|c| c.eq_ignore_ascii_case(letter)),
+        };
+        match self.die_letters.iter().find(used) {
+            Some(letter) => Err(errors::ParseError::InvalidDieLetter(
+                *letter,
+                Default::default(),
+            )),
+            None => Ok(()),
+        }
+    }
+    /// Reads `input` into the characters of the standard notation with whitespace removed, along with the
+    /// bytes each of them starts and ends at.
+    fn read(&self, input: &str) -> (Vec<usize>, Vec<usize>, Vec<char>) {
+        let mut offsets = Vec::with_capacity(input.len());
+        let mut ends = Vec::with_capacity(input.len());
+        let mut chars = Vec::with_capacity(input.len());
+        let mut in_label = false;
+        let mut in_variable = false;
+        for (at, c) in input.char_indices().filter(|(_, c)| !c.is_whitespace()) {
+            // a variable name ends at whitespace, so "@x d6" is the variable x followed by a d6
+            in_variable &= ends.last() == Some(&at) && (c.is_ascii_alphanumeric() || c == '_');
+            let kept = in_label || in_variable;
+            match c {
+                '[' => in_label = true,
+                ']' => in_label = false,
+                '@' => in_variable = true,
+                _ => {}
+            }
+            offsets.push(at);
+            ends.push(at + c.len_utf8());
+            let c = if kept {
+                c
+            } else {
+                self.translate(c, chars.last())
+            };
+            chars.push(c);
+        }
+        (offsets, ends, chars)
+    }
+    /// The character `c` stands for in the standard notation when it follows the character `previous` stands
+    /// for.
+    fn translate(&self, c: char, previous: Option<&char>) -> char {
+        let c = match c {
+            '×' if self.unicode_operators => '*',
+            '÷' if self.unicode_operators => '/',
+            '−' if self.unicode_operators => '-',
+            // the sides of a Fate die, anywhere else it is the `f` of a failure or a function such as "FLOOR"
+            'F' if previous == Some(&'d') => 'F',
+            c if !self.case_sensitive => c.to_ascii_lowercase(),
+            c => c,
+        };
+        let is_die = |letter: &char| match self.case_sensitive {
+            true => *letter == c,
+            false => letter.eq_ignore_ascii_case(&c),
+        };
+        match self.die_letters.iter().any(is_die) {
+            true => 'd',
+            false => c,
+        }
+    }
+}
+impl Default for Dialect {
+    fn default() -> Dialect {
+        Dialect {
+            die_letters: Vec::new(),
+            unicode_operators: true,
+            case_sensitive: false,
+        }
+    }
+}
+/// A compiled token, only public so the `dice!` macro can build equations that were compiled ahead of time.
+#[doc(hidden)]
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub enum Token {
+    Operand(u32),
+    Plus,
+    Minus,
+    Times,
+    Divide,
+    Exponent,
+    L,
+    /// a `-` with no value before it such as the one in `2^-1`, which only waits on the operator stack and is
+    /// output as a subtraction from 0
+    Negate,
+    Dice(Die),
+    Variable(String),
+    /// a call to a built in function and the number of arguments it was given
+    Function(Function, u32),
+    /// evaluates the next n tokens keeping any fractions and rounds the result, for `floor()`, `ceil()`
+    /// and `round()`
+    Rounded(RoundingPolicy, usize),
+    /// adds the value on top of the stack to the subtotal for a label such as `[fire]`
+    Label(String),
+    /// evaluates the next n tokens as many times as the value on the stack, giving a list of results
+    Repeat(usize),
+    /// a die whose number of dice, and sides when `sides` is set, are popped from the stack
+    DynamicDice {
+        die: Die,
+        sides: bool,
+    },
+    /// `==`, `!=`, `<`, `<=`, `>` and `>=` giving 1 when true and 0 when false
+    Compare(CompareOp),
+    /// `&&` skips the next n tokens leaving the 0 on the stack when the left hand side is 0
+    And(usize),
+    /// `||` skips the next n tokens leaving a 1 on the stack when the left hand side is not 0
+    Or(usize),
+    /// turns the value on top of the stack into 1 if it is not 0
+    Truthy,
+    /// the `?` of a ternary, pops the condition and skips the next n tokens when it is 0
+    JumpIfFalse(usize),
+    /// the `:` of a ternary, skips the next n tokens
+    Jump(usize),
+}
+/// The functions that can be called in an equation such as `max(1, 1d6-2)`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Function {
+    Min,
+    Max,
+    Sum,
+    Abs,
+    Floor,
+    Ceil,
+    Round,
+    Clamp,
+}
+impl Function {
+    fn from_name(name: &str) -> Option<Function> {
+        match name {
+            "min" => Some(Function::Min),
+            "max" => Some(Function::Max),
+            "sum" => Some(Function::Sum),
+            "abs" => Some(Function::Abs),
+            "floor" => Some(Function::Floor),
+            "ceil" => Some(Function::Ceil),
+            "round" => Some(Function::Round),
+            "clamp" => Some(Function::Clamp),
+            _ => None,
+        }
+    }
+    /// The name the function is called by in an equation.
+    pub fn name(&self) -> &'static str {
+        match self {
+            Function::Min => "min",
+            Function::Max => "max",
+            Function::Sum => "sum",
+            Function::Abs => "abs",
+            Function::Floor => "floor",
+            Function::Ceil => "ceil",
+            Function::Round => "round",
+            Function::Clamp => "clamp",
+        }
+    }
+    /// Checks if the function can be called with `count` arguments.
+    fn accepts(&self, count: u32) -> bool {
+        match self {
+            Function::Min | Function::Max | Function::Sum => count >= 1,
+            Function::Clamp => count == 3,
+            _ => count == 1,
+        }
+    }
+    /// How the function rounds its argument, for `floor()`, `ceil()` and `round()`.
+    fn rounding(&self) -> Option<RoundingPolicy> {
+        match self {
+            Function::Floor => Some(RoundingPolicy::Floor),
+            Function::Ceil => Some(RoundingPolicy::Ceil),
+            Function::Round => Some(RoundingPolicy::HalfUp),
+            _ => None,
+        }
+    }
+}
+#[doc(hidden)]
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct Die {
+    pub number: u32,
+    pub kind: DieKind,
+    pub keep: Option<Keep>,
+    pub explode: Option<Explode>,
+    pub reroll: Option<Reroll>,
+    pub success: Option<Compare>,
+    pub failure: Option<Compare>,
+}
+/// The faces a single die can land on.
+#[doc(hidden)]
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub enum DieKind {
+    /// `dM` and `d%` faces numbered 1 through M
+    Standard(u32),
+    /// `dF` Fate dice with the faces -1, 0 and +1
+    Fudge,
+    /// `d{1,1,2,3,5,8}` dice with every face listed, sorted from lowest to highest
+    Custom(Vec<i32>),
+}
+/// Which of the rolled dice count towards the total, e.g. `4d6kh3` or `4d6dl1`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Keep {
+    Highest(u32),
+    Lowest(u32),
+    DropHighest(u32),
+    DropLowest(u32),
+}
+/// Rolls another die when a die lands on its highest face, or on faces matching `compare`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct Explode {
+    pub kind: ExplodeKind,
+    /// the faces that explode, or only the highest face when there is no compare point
+    pub compare: Option<Compare>,
+}
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum ExplodeKind {
+    /// `!` each explosion adds a new die to the pool
+    Standard,
+    /// `!!` each explosion is added onto the die that exploded
+    Compound,
+    /// `!p` like standard but every extra die has 1 subtracted from it
+    Penetrate,
+}
+/// Rolls a die again while it lands on a face matching `compare`, or only once with `ro`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct Reroll {
+    pub once: bool,
+    pub compare: Compare,
+}
+/// A compare point such as the `>8` in `d10!>8`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct Compare {
+    pub op: CompareOp,
+    pub value: i32,
+}
+/// How two values are compared, by `==` or `<` between expressions or by a compare point of a die.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum CompareOp {
+    Equal,
+    NotEqual,
+    Greater,
+    GreaterEqual,
+    Less,
+    LessEqual,
+}
+impl Compare {
+    #[doc(hidden)]
+    pub fn matches(&self, face: i64) -> bool {
+        self.op.apply(face, self.value as i64)
+    }
+}
+impl CompareOp {
+    #[doc(hidden)]
+    pub fn apply<T: PartialOrd>(self, lhs: T, rhs: T) -> bool {
+        match self {
+            CompareOp::Equal => lhs == rhs,
+            CompareOp::NotEqual => lhs != rhs,
+            CompareOp::Greater => lhs > rhs,
+            CompareOp::GreaterEqual => lhs >= rhs,
+            CompareOp::Less => lhs < rhs,
+            CompareOp::LessEqual => lhs <= rhs,
+        }
+    }
+}
+impl Die {
+    pub fn new(number: u32, kind: DieKind) -> Die {
+        Die {
+            number,
+            kind,
+            keep: None,
+            explode: None,
+            reroll: None,
+            success: None,
+            failure: None,
+        }
+    }
+    /// Checks if the die has no modifiers and can be rolled and averaged directly.
+    pub fn is_simple(&self) -> bool {
+        self.keep.is_none()
+            && self.explode.is_none()
+            && self.reroll.is_none()
+            && self.success.is_none()
+    }
+    /// What a die showing `value` adds to a success counting pool, 1 for a success and -1 for a failure.
+    pub fn score(&self, value: i64) -> i64 {
+        let success = self.success.is_some_and(|c| c.matches(value)) as i64;
+        let failure = self.failure.is_some_and(|c| c.matches(value)) as i64;
+        success - failure
+    }
+    /// Checks if every face of a single die matches `compare`, worked out from the lowest and highest face
+    /// so dice with huge numbers of sides are never listed. Always false while its sides are an expression
+    /// that hasn't been worked out yet.
+    pub fn every_face_matches(&self, compare: &Compare) -> bool {
+        let (min, max) = (self.min_face() as i64, self.max_face() as i64);
+        let value = compare.value as i64;
+        match &self.kind {
+            DieKind::Standard(0) => false,
+            DieKind::Custom(faces) => faces.iter().all(|&face| compare.matches(face as i64)),
+            _ => match compare.op {
+                CompareOp::Equal => min == value && max == value,
+                CompareOp::NotEqual => value < min || value > max,
+                CompareOp::Greater => min > value,
+                CompareOp::GreaterEqual => min >= value,
+                CompareOp::Less => max < value,
+                CompareOp::LessEqual => max <= value,
+            },
+        }
+    }
+    pub fn min_face(&self) -> i32 {
+        match &self.kind {
+            DieKind::Standard(sides) => (*sides).min(1) as i32,
+            DieKind::Fudge => -1,
+            DieKind::Custom(faces) => faces[0],
+        }
+    }
+    pub fn max_face(&self) -> i32 {
+        match &self.kind {
+            DieKind::Standard(sides) => *sides as i32,
+            DieKind::Fudge => 1,
+            DieKind::Custom(faces) => faces[faces.len() - 1],
+        }
+    }
+    /// The average face of a single die with no modifiers.
+    pub fn mean_face(&self) -> f64 {
+        match &self.kind {
+            DieKind::Custom(faces) => {
+                faces.iter().map(|&f| f as f64).sum::<f64>() / faces.len() as f64
+            }
+            _ => (self.min_face() + self.max_face()) as f64 / 2.0,
+        }
+    }
+    /// Checks if a die showing `face` should explode.
+    pub fn explodes(&self, face: i64) -> bool {
+        match self.explode {
+            Some(Explode {
+                compare: Some(compare),
+                ..
+            }) => compare.matches(face),
+            Some(_) => face == self.max_face() as i64,
+            None => false,
+        }
+    }
+    /// Checks if the die has more sides than `limits` allow, a die with listed faces counts each face as a side.
+    pub fn too_many_sides(&self, limits: &Limits) -> bool {
+        match &self.kind {
+            DieKind::Standard(sides) => *sides > limits.max_sides,
+            DieKind::Fudge => false,
+            DieKind::Custom(faces) => faces.len() > limits.max_sides as usize,
+        }
+    }
+    /// Combines two plain dice of the same kind that are added together, such as "2d6+4d6" into "6d6".
+    ///
+    /// Dice are only merged when each average is a whole number, so averages that have to be rounded
+    /// come out the same as rolling the dice separately. An emphasis roll then picks between two rolls
+    /// of the merged pool.
+    fn merged(&self, other: &Die) -> Option<Die> {
+        let plain = |die: &Die| die.is_simple() && die.failure.is_none();
+        if !plain(self) || !plain(other) || self.kind != other.kind {
+            return None;
+        }
+        let whole_average = |number: u32| match &self.kind {
+            DieKind::Standard(sides) => (number as u64 * (*sides as u64 + 1)).is_multiple_of(2),
+            DieKind::Fudge => true,
+            DieKind::Custom(faces) => {
+                let sum: i64 = faces.iter().map(|&face| face as i64).sum();
+                (number as i64 * sum) % faces.len() as i64 == 0
+            }
+        };
+        if !whole_average(self.number) || !whole_average(other.number) {
+            return None;
+        }
+        Some(Die::new(
+            self.number.checked_add(other.number)?,
+            self.kind.clone(),
+        ))
+    }
+    /// The number of dice that are counted after keep / drop modifiers are applied.
+    pub fn kept(&self) -> u32 {
+        let (start, end) = self.kept_range(self.number);
+        end - start
+    }
+    /// Returns the range of indices that are kept once `n` rolled dice are sorted lowest to highest.
+    pub fn kept_range(&self, n: u32) -> (u32, u32) {
+        match self.keep {
+            None => (0, n),
+            Some(Keep::Highest(k)) => (n.saturating_sub(k), n),
+            Some(Keep::Lowest(k)) => (0, k.min(n)),
+            Some(Keep::DropHighest(k)) => (0, n.saturating_sub(k)),
+            Some(Keep::DropLowest(k)) => (k.min(n), n),
+        }
+    }
+}
+
+/// Compiles `input` written in `dialect` into tokens that must stay within `limits`, along with the bytes of
+/// `input` each token came from.
+#[doc(hidden)]
+pub fn compile(
+    input: &str,
+    dialect: &Dialect,
+    limits: &Limits,
+) -> Result<(Vec<Token>, Vec<Range<usize>>), errors::ParseError> {
+    let (tokens, spans) = parse(input, dialect, limits)?;
+    simplify(tokens, spans).map_err(|err| err.in_input(input))
+}
+/// Compiles `input` the same as [`compile`] without folding or merging anything, giving the tokens of the
+/// equation as it was written.
+#[doc(hidden)]
+pub fn parse(
+    input: &str,
+    dialect: &Dialect,
+    limits: &Limits,
+) -> Result<(Vec<Token>, Vec<Range<usize>>), errors::ParseError> {
+    dialect.check()?;
+    // an equation that is too long isn't shown with the error, it could be huge
+    check_length(input, limits)?;
+    check_depth(input, limits)
+        .and_then(|_| infix_to_postfix(input, dialect, limits))
+        .map_err(|err| err.in_input(input))
+}
+/// Checks that `input` isn't longer than the limit, before any time is spent compiling it.
+fn check_length(input: &str, limits: &Limits) -> Result<(), errors::ParseError> {
+    if input.len() > limits.max_length {
+        return Err(errors::ParseError::TooLong(Default::default()));
+    }
+    Ok(())
+}
+/// Checks that parentheses aren't nested more deeply than the limit, ignoring any inside of a label.
+fn check_depth(input: &str, limits: &Limits) -> Result<(), errors::ParseError> {
+    let mut depth = 0;
+    let mut in_label = false;
+    for (at, c) in input.char_indices() {
+        match c {
+            '[' => in_label = true,
+            ']' => in_label = false,
+            '(' if !in_label => {
+                depth += 1;
+                if depth > limits.max_depth {
+                    return Err(errors::ParseError::TooDeep(Default::default()).at(at..at + 1));
+                }
+            }
+            ')' if !in_label => depth = depth.saturating_sub(1),
+            _ => {}
+        }
+    }
+    Ok(())
+}
+/// What is known at compile time about a value that will be on the stack while rolling.
+enum Known {
+    /// a whole number that comes out the same however the equation is rolled
+    Constant(i64),
+    /// a die token that could be merged with another
+    Dice,
+    Other,
+}
+/// Works out the parts of the equation that don't depend on any dice or variables, and merges like dice added
+/// together such as "1d6+3d6", so they aren't worked out again on every roll.
+///
+/// Only whole results from 0 to `i32::MAX` are folded, so folding gives the same answer in every number mode
+/// and rounding policy. A division by a constant 0 that is sure to run is an error.
+fn simplify(
+    tokens: Vec<Token>,
+    spans: Vec<Range<usize>>,
+) -> Result<(Vec<Token>, Vec<Range<usize>>), errors::ParseError> {
+    // values from before the landing of a jump could have come from either path, so nothing is known about them,
+    // and tokens that can be skipped might never run
+    let mut landings = vec![false; tokens.len() + 1];
+    let mut skippable = vec![0; tokens.len() + 1];
+    for (at, token) in tokens.iter().enumerate() {
+        // the argument of a rounding function always runs, but keeps its fractions so can't be folded into
+        // what is around it
+        if let Token::Rounded(_, length) = token {
+            landings[(at + 1 + length).min(tokens.len())] = true;
+        }
+        if let Token::Repeat(skip)
+        | Token::And(skip)
+        | Token::Or(skip)
+        | Token::JumpIfFalse(skip)
+        | Token::Jump(skip) = token
+        {
+            let end = (at + 1 + skip).min(tokens.len());
+            landings[end] = true;
+            skippable[at + 1] += 1;
+            skippable[end] -= 1;
+        }
+    }
+    let mut output: Vec<Token> = Vec::with_capacity(tokens.len());
+    let mut output_spans: Vec<Range<usize>> = Vec::with_capacity(tokens.len());
+    // the token each output token came from and where each token ended up, for moving the jumps
+    let mut origins: Vec<usize> = Vec::with_capacity(tokens.len());
+    let mut moved: Vec<usize> = Vec::with_capacity(tokens.len() + 1);
+    let mut stack: Vec<Known> = Vec::new();
+    let mut skipped = 0;
+    for (at, (token, span)) in tokens.into_iter().zip(spans).enumerate() {
+        moved.push(output.len());
+        skipped += skippable[at];
+        let jumps = matches!(
+            token,
+            Token::Repeat(_)
+                | Token::Rounded(..)
+                | Token::And(_)
+                | Token::Or(_)
+                | Token::JumpIfFalse(_)
+                | Token::Jump(_)
+        );
+        if landings[at] || jumps {
+            stack.iter_mut().for_each(|known| *known = Known::Other);
+        }
+        let (popped, pushes) = match &token {
+            Token::Operand(_) | Token::Variable(_) | Token::Dice(_) => (0, true),
+            Token::DynamicDice { sides, .. } => (1 + *sides as usize, true),
+            Token::Function(_, count) => (*count as usize, true),
+            Token::Label(_) | Token::Truthy => (1, true),
+            Token::Repeat(_) | Token::And(_) | Token::Or(_) | Token::JumpIfFalse(_) => (1, false),
+            Token::Rounded(..) | Token::Jump(_) | Token::L | Token::Negate => (0, false),
+            _ => (2, true),
+        };
+        let operands = stack.split_off(stack.len().saturating_sub(popped));
+        if matches!(token, Token::Divide)
+            && matches!(operands.get(1), Some(Known::Constant(0)))
+            && skipped == 0
+        {
+            return Err(errors::ParseError::DivideByZero(Default::default()).at(span));
+        }
+        let start = output.len().saturating_sub(popped);
+        let folded = match (&token, &operands[..], &output[start..]) {
+            (Token::Operand(value), _, _) => {
+                let known = match i32::try_from(*value) {
+                    Ok(value) => Known::Constant(value as i64),
+                    Err(_) => Known::Other,
+                };
+                stack.push(known);
+                None
+            }
+            (Token::Dice(_), _, _) => {
+                stack.push(Known::Dice);
+                None
+            }
+            (Token::Plus, [Known::Dice, Known::Dice], [Token::Dice(lhs), Token::Dice(rhs)]) => {
+                lhs.merged(rhs).map(|die| (Token::Dice(die), Known::Dice))
+            }
+            _ => fold(&token, &operands)
+                .map(|value| (Token::Operand(value as u32), Known::Constant(value))),
+        };
+        match folded {
+            Some((token, known)) if operands.len() == popped => {
+                let span = output_spans[start..].iter().fold(span, |span, operand| {
+                    span.start.min(operand.start)..span.end.max(operand.end)
+                });
+                output.truncate(start);
+                output_spans.truncate(start);
+                origins.truncate(start);
+                output.push(token);
+                output_spans.push(span);
+                origins.push(at);
+                stack.push(known);
+            }
+            _ => {
+                if pushes && !matches!(token, Token::Operand(_) | Token::Dice(_)) {
+                    stack.push(Known::Other);
+                }
+                output.push(token);
+                output_spans.push(span);
+                origins.push(at);
+            }
+        }
+    }
+    moved.push(output.len());
+    // nothing is folded across a landing, so the tokens jumped to are where they were when they were reached
+    for (index, token) in output.iter_mut().enumerate() {
+        if let Token::Repeat(skip)
+        | Token::Rounded(_, skip)
+        | Token::And(skip)
+        | Token::Or(skip)
+        | Token::JumpIfFalse(skip)
+        | Token::Jump(skip) = token
+        {
+            let end = (origins[index] + 1 + *skip).min(moved.len() - 1);
+            *skip = moved[end] - index - 1;
+        }
+    }
+    Ok((output, output_spans))
+}
+/// Works out `token` when all of its operands are known, giving `None` when the result isn't a whole number
+/// from 0 to `i32::MAX` or could differ between number modes.
+fn fold(token: &Token, operands: &[Known]) -> Option<i64> {
+    let values = operands
+        .iter()
+        .map(|known| match known {
+            Known::Constant(value) => Some(*value),
+            _ => None,
+        })
+        .collect::<Option<Vec<i64>>>()?;
+    let value = match (token, &values[..]) {
+        (Token::Plus, [lhs, rhs]) => lhs.checked_add(*rhs)?,
+        (Token::Minus, [lhs, rhs]) => lhs.checked_sub(*rhs)?,
+        (Token::Times, [lhs, rhs]) => lhs.checked_mul(*rhs)?,
+        // an exact answer doesn't depend on the rounding policy or on keeping fractions
+        (Token::Divide, [lhs, rhs]) if *rhs != 0 && lhs % rhs == 0 => lhs / rhs,
+        (Token::Exponent, [base, exponent]) => base.checked_pow(u32::try_from(*exponent).ok()?)?,
+        (Token::Compare(op), [lhs, rhs]) => op.apply(lhs, rhs) as i64,
+        (Token::Function(function, _), values) => match (function, values) {
+            (Function::Min, values) => *values.iter().min()?,
+            (Function::Max, values) => *values.iter().max()?,
+            (Function::Sum, values) => {
+                values.iter().try_fold(0i64, |sum, &v| sum.checked_add(v))?
+            }
+            (Function::Clamp, [value, low, high]) => (*value).max(*low).min(*high),
+            (Function::Abs, [value]) => value.abs(),
+            _ => return None,
+        },
+        _ => return None,
+    };
+    (0..=i32::MAX as i64).contains(&value).then_some(value)
+}
+/// Compiles `input` into postfix tokens along with the byte range of the input each token came from.
+///
+/// Each die is checked against `limits` as soon as its sides are read, dice whose number or sides are worked out
+/// when rolling are checked once they are rolled instead.
+fn infix_to_postfix(
+    input: &str,
+    dialect: &Dialect,
+    limits: &Limits,
+) -> Result<(Vec<Token>, Vec<Range<usize>>), errors::ParseError> {
+    let mut output_queue: Vec<(Token, Range<usize>)> = Vec::with_capacity(input.len());
+    let mut operator_stack: Vec<(Token, Range<usize>)> = Vec::with_capacity(input.len());
+    let mut last_token_was_operand = false;
+    // set after a die or variable, which can be multiplied like an operand but can't have digits added to it
+    let mut last_token_was_value = false;
+    // number of arguments seen so far for each function call that is still open
+    let mut argument_counts: Vec<u32> = Vec::new();
+    // the dice written in the equation so far, to check against the limit
+    let mut dice_count: u64 = 0;
+    let mut error = None;
+    let (offsets, ends, chars) = dialect.read(input);
+    // the bytes of the input from the character at `from` up to the one before `to`
+    let span = |from: usize, to: usize| offsets[from]..ends[to - 1];
+    let mut position = 0;
+    let mut start = 0;
+
+    while let Some(&token) = chars.get(position) {
+        start = position;
+        position += 1;
+        match token {
+            '0'..='9' => {
+                let digit = token.to_digit(10).unwrap_or(0);
+                let (value, first) = match output_queue.last() {
+                    Some((Token::Operand(value), digits)) if last_token_was_operand => {
+                        let (value, first) = (*value, digits.start);
+                        output_queue.pop();
+                        let value = value
+                            .checked_mul(10)
+                            .and_then(|value| value.checked_add(digit))
+                            .filter(|&value| value <= i32::MAX as u32);
+                        (value, first)
+                    }
+                    _ => (Some(digit), offsets[start]),
+                };
+                let digits = first..span(start, position).end;
+                match value {
+                    Some(value) => output_queue.push((Token::Operand(value), digits)),
+                    None => {
+                        error = Some(errors::ParseError::Overflow(Default::default()).at(digits));
+                        break;
+                    }
+                }
+                last_token_was_operand = true;
+            }
+            '(' => {
+                if let Some(&(Token::Function(function, _), _)) = operator_stack.last() {
+                    argument_counts.push(1);
+                    // the argument of a rounding function is evaluated on its own, like the body of a repeat
+                    if let Some(rounding) = function.rounding() {
+                        operator_stack.push((
+                            Token::Rounded(rounding, output_queue.len()),
+                            span(start, position),
+                        ));
+                        output_queue.push((Token::Rounded(rounding, 0), span(start, position)));
+                    }
+                } else if last_token_was_operand | last_token_was_value {
+                    operator_stack.push((Token::Times, span(start, position)));
+                }
+                operator_stack.push((Token::L, span(start, position)));
+                last_token_was_operand = false;
+                last_token_was_value = false;
+            }
+            ')' => {
+                let mut opened = false;
+                while let Some(operator) = operator_stack.pop() {
+                    if let (Token::L, _) = operator {
+                        opened = true;
+                        break;
+                    } else {
+                        emit(&mut output_queue, operator)?;
+                    }
+                }
+                if !opened {
+                    error = Some(errors::ParseError::InvalidExpression(Default::default()));
+                    break;
+                }
+                let closed = span(start, position).end;
+                let rounded = match operator_stack.last() {
+                    Some(&(Token::Rounded(rounding, placeholder), _)) => {
+                        operator_stack.pop();
+                        Some((rounding, placeholder))
+                    }
+                    _ => None,
+                };
+                if let Some((Token::Function(function, _), name)) = operator_stack.last() {
+                    let function = *function;
+                    let call = name.start..closed;
+                    operator_stack.pop();
+                    let count = argument_counts.pop().unwrap_or(0);
+                    if !function.accepts(count) {
+                        error = Some(
+                            errors::ParseError::InvalidExpression(Default::default()).at(call),
+                        );
+                        break;
+                    }
+                    match rounded {
+                        Some((rounding, placeholder)) => {
+                            let length = output_queue.len() - placeholder - 1;
+                            output_queue[placeholder] = (Token::Rounded(rounding, length), call);
+                        }
+                        None => output_queue.push((Token::Function(function, count), call)),
+                    }
+                }
+                if let Some((Token::Repeat(placeholder), _)) = operator_stack.last() {
+                    let placeholder = *placeholder;
+                    operator_stack.pop();
+                    output_queue[placeholder].0 =
+                        Token::Repeat(output_queue.len() - placeholder - 1);
+                }
+                if let Err(err) = finish_dynamic_dice(
+                    &chars,
+                    (&offsets, &ends),
+                    &mut position,
+                    &mut operator_stack,
+                    &mut output_queue,
+                ) {
+                    error = Some(err);
+                    break;
+                }
+                last_token_was_operand = false;
+                last_token_was_value = true;
+            }
+            'x' | '#' => {
+                // the number of repeats is the operand or group just before, and the repeated
+                // expression has to be in parentheses
+                if !(last_token_was_operand | last_token_was_value)
+                    || chars.get(position) != Some(&'(')
+                {
+                    error = Some(errors::ParseError::InvalidToken(token, Default::default()));
+                    break;
+                }
+                operator_stack.push((Token::Repeat(output_queue.len()), span(start, position)));
+                output_queue.push((Token::Repeat(0), span(start, position)));
+                last_token_was_operand = false;
+                last_token_was_value = false;
+            }
+            '[' => {
+                // labels keep their spaces, so they are read from the input rather than `chars`
+                let label_start = offsets[position - 1] + 1;
+                let label = match input[label_start..].find(']') {
+                    Some(end) if last_token_was_operand | last_token_was_value => {
+                        input[label_start..label_start + end].trim()
+                    }
+                    _ => {
+                        error = Some(errors::ParseError::InvalidToken(token, Default::default()));
+                        break;
+                    }
+                };
+                while chars[position] != ']' {
+                    position += 1;
+                }
+                position += 1;
+                output_queue.push((Token::Label(label.to_string()), span(start, position)));
+                last_token_was_operand = false;
+                last_token_was_value = true;
+            }
+            ',' => {
+                while let Some((top, _)) = operator_stack.last() {
+                    if let Token::L = top {
+                        break;
+                    }
+                    emit(&mut output_queue, operator_stack.pop().unwrap())?;
+                }
+                match argument_counts.last_mut() {
+                    Some(count) => *count += 1,
+                    None => {
+                        error = Some(errors::ParseError::InvalidToken(token, Default::default()));
+                        break;
+                    }
+                }
+                last_token_was_operand = false;
+                last_token_was_value = false;
+            }
+            '+' => {
+                if !last_token_was_operand && !last_token_was_value {
+                    output_queue.push((Token::Operand(0), span(start, position)));
+                }
+                let token_precedence = operator_precedence(&Token::Plus);
+                while let Some((top, _)) = operator_stack.last() {
+                    if let Token::L = top {
+                        break;
+                    } else {
+                        let top_precedence = operator_precedence(top);
+                        if token_precedence <= top_precedence {
+                            emit(&mut output_queue, operator_stack.pop().unwrap())?;
+                        } else {
+                            break;
+                        }
+                    }
+                }
+                operator_stack.push((Token::Plus, span(start, position)));
+                last_token_was_operand = false;
+                last_token_was_value = false;
+            }
+            '-' => {
+                // a minus with no value before it negates only the value after it, so "2^-1" is "2^(0-1)" and
+                // "2*-3" is "2*(0-3)"
+                if !last_token_was_operand && !last_token_was_value {
+                    output_queue.push((Token::Operand(0), span(start, position)));
+                    operator_stack.push((Token::Negate, span(start, position)));
+                    continue;
+                }
+                let token_precedence = operator_precedence(&Token::Minus);
+                while let Some((top, _)) = operator_stack.last() {
+                    if let Token::L = top {
+                        break;
+                    } else {
+                        let top_precedence = operator_precedence(top);
+                        if token_precedence <= top_precedence {
+                            emit(&mut output_queue, operator_stack.pop().unwrap())?;
+                        } else {
+                            break;
+                        }
+                    }
+                }
+                operator_stack.push((Token::Minus, span(start, position)));
+                last_token_was_operand = false;
+                last_token_was_value = false;
+            }
+            '*' => {
+                let token_precedence = operator_precedence(&Token::Times);
+                while let Some((top, _)) = operator_stack.last() {
+                    if let Token::L = top {
+                        break;
+                    } else {
+                        let top_precedence = operator_precedence(top);
+                        if token_precedence <= top_precedence {
+                            emit(&mut output_queue, operator_stack.pop().unwrap())?;
+                        } else {
+                            break;
+                        }
+                    }
+                }
+                operator_stack.push((Token::Times, span(start, position)));
+                last_token_was_operand = false;
+                last_token_was_value = false;
+            }
+            '/' => {
+                let token_precedence = operator_precedence(&Token::Divide);
+                while let Some((top, _)) = operator_stack.last() {
+                    if let Token::L = top {
+                        break;
+                    } else {
+                        let top_precedence = operator_precedence(top);
+                        if token_precedence <= top_precedence {
+                            emit(&mut output_queue, operator_stack.pop().unwrap())?;
+                        } else {
+                            break;
+                        }
+                    }
+                }
+                operator_stack.push((Token::Divide, span(start, position)));
+                last_token_was_operand = false;
+                last_token_was_value = false;
+            }
+            '^' => {
+                // exponents are right associative so "2^3^2" is "2^(3^2)", only tighter operators are popped
+                let token_precedence = operator_precedence(&Token::Exponent);
+                pop_operators(&mut operator_stack, &mut output_queue, token_precedence + 1)?;
+                operator_stack.push((Token::Exponent, span(start, position)));
+                last_token_was_operand = false;
+                last_token_was_value = false;
+            }
+            'd' => {
+                // after a parenthesised group, variable or die the number of dice is already on the stack
+                let mut count = offsets[start]..offsets[start];
+                let number = if last_token_was_operand {
+                    match output_queue.pop() {
+                        Some((Token::Operand(die_count), digits)) => {
+                            count = digits;
+                            Some(die_count)
+                        }
+                        _ => Some(1),
+                    }
+                } else if last_token_was_value {
+                    None
+                } else {
+                    Some(1)
+                };
+                if let Some('(' | '@') = chars.get(position) {
+                    // the sides are an expression, so the die is rolled once they have been worked out
+                    let first = count.start;
+                    if let Some(number) = number {
+                        output_queue.push((Token::Operand(number), count));
+                    }
+                    operator_stack.push((
+                        Token::DynamicDice {
+                            die: Die::new(0, DieKind::Standard(0)),
+                            sides: true,
+                        },
+                        first..span(start, position).end,
+                    ));
+                    last_token_was_operand = false;
+                    last_token_was_value = false;
+                    continue;
+                }
+                // the die starts at its count, and may have been read past the end looking for a `}`
+                let dice =
+                    |position: usize| count.start..span(start, position.min(chars.len())).end;
+                let mut die = Die::new(
+                    number.unwrap_or(0),
+                    match parse_die_kind(&chars, &mut position) {
+                        Ok(kind) => kind,
+                        Err(err) => {
+                            error = Some(err.at(dice(position)));
+                            break;
+                        }
+                    },
+                );
+                // checked before any modifiers are read, so nothing is worked out for a die that is too large
+                if die.too_many_sides(limits) {
+                    error = Some(
+                        errors::ParseError::TooManySides(Default::default()).at(dice(position)),
+                    );
+                    break;
+                }
+                dice_count = dice_count.saturating_add(number.unwrap_or(0) as u64);
+                if dice_count > limits.max_dice {
+                    error = Some(
+                        errors::ParseError::TooManyDice(Default::default()).at(dice(position)),
+                    );
+                    break;
+                }
+                if let Err(err) =
+                    parse_die_modifiers(&chars, (&offsets, &ends), &mut position, &mut die)
+                {
+                    error = Some(err.at(dice(position)));
+                    break;
+                }
+                match number {
+                    Some(_) => output_queue.push((Token::Dice(die), dice(position))),
+                    None => output_queue
+                        .push((Token::DynamicDice { die, sides: false }, dice(position))),
+                }
+                last_token_was_operand = false;
+                last_token_was_value = true;
+            }
+            '<' | '>' | '=' | '!' => {
+                let op = match (token, chars.get(position)) {
+                    ('<', Some('=')) => CompareOp::LessEqual,
+                    ('>', Some('=')) => CompareOp::GreaterEqual,
+                    ('=', Some('=')) => CompareOp::Equal,
+                    ('!', Some('=')) => CompareOp::NotEqual,
+                    ('<', _) => CompareOp::Less,
+                    ('>', _) => CompareOp::Greater,
+                    _ => {
+                        error = Some(errors::ParseError::InvalidToken(token, Default::default()));
+                        break;
+                    }
+                };
+                if matches!(
+                    op,
+                    CompareOp::LessEqual
+                        | CompareOp::GreaterEqual
+                        | CompareOp::Equal
+                        | CompareOp::NotEqual
+                ) {
+                    position += 1;
+                }
+                pop_operators(
+                    &mut operator_stack,
+                    &mut output_queue,
+                    operator_precedence(&Token::Compare(op)),
+                )?;
+                operator_stack.push((Token::Compare(op), span(start, position)));
+                last_token_was_operand = false;
+                last_token_was_value = false;
+            }
+            '&' | '|' => {
+                if chars.get(position) != Some(&token) {
+                    error = Some(errors::ParseError::InvalidToken(token, Default::default()));
+                    break;
+                }
+                position += 1;
+                let operator = |at| {
+                    if token == '&' {
+                        Token::And(at)
+                    } else {
+                        Token::Or(at)
+                    }
+                };
+                pop_operators(
+                    &mut operator_stack,
+                    &mut output_queue,
+                    operator_precedence(&operator(0)),
+                )?;
+                // the placeholder is filled in with how far to skip once the right hand side is known
+                operator_stack.push((operator(output_queue.len()), span(start, position)));
+                output_queue.push((operator(0), span(start, position)));
+                last_token_was_operand = false;
+                last_token_was_value = false;
+            }
+            '?' => {
+                pop_operators(&mut operator_stack, &mut output_queue, 1)?;
+                operator_stack.push((
+                    Token::JumpIfFalse(output_queue.len()),
+                    span(start, position),
+                ));
+                output_queue.push((Token::JumpIfFalse(0), span(start, position)));
+                last_token_was_operand = false;
+                last_token_was_value = false;
+            }
+            ':' => {
+                while let Some((top, _)) = operator_stack.last() {
+                    if let Token::L | Token::JumpIfFalse(_) = top {
+                        break;
+                    }
+                    emit(&mut output_queue, operator_stack.pop().unwrap())?;
+                }
+                match operator_stack.pop() {
+                    Some((Token::JumpIfFalse(question), _)) => {
+                        // skip the rest of the true branch along with the jump over the false branch
+                        output_queue[question].0 =
+                            Token::JumpIfFalse(output_queue.len() - question);
+                        operator_stack
+                            .push((Token::Jump(output_queue.len()), span(start, position)));
+                        output_queue.push((Token::Jump(0), span(start, position)));
+                    }
+                    _ => {
+                        error = Some(errors::ParseError::InvalidToken(token, Default::default()));
+                        break;
+                    }
+                }
+                last_token_was_operand = false;
+                last_token_was_value = false;
+            }
+            '@' => {
+                let name_start = position;
+                while chars
+                    .get(position)
+                    .is_some_and(|c| c.is_ascii_alphanumeric() || *c == '_')
+                    && offsets[position] == ends[position - 1]
+                {
+                    position += 1;
+                }
+                if name_start == position {
+                    error = Some(errors::ParseError::InvalidToken(token, Default::default()));
+                    break;
+                }
+                if last_token_was_operand | last_token_was_value {
+                    operator_stack.push((Token::Times, span(start, position)));
+                }
+                output_queue.push((
+                    Token::Variable(chars[name_start..position].iter().collect()),
+                    span(start, position),
+                ));
+                if let Err(err) = finish_dynamic_dice(
+                    &chars,
+                    (&offsets, &ends),
+                    &mut position,
+                    &mut operator_stack,
+                    &mut output_queue,
+                ) {
+                    error = Some(err);
+                    break;
+                }
+                last_token_was_operand = false;
+                last_token_was_value = true;
+            }
+            'a'..='z' => {
+                while chars.get(position).is_some_and(|c| c.is_ascii_lowercase()) {
+                    position += 1;
+                }
+                let name: String = chars[start..position].iter().collect();
+                match Function::from_name(&name) {
+                    Some(function) if chars.get(position) == Some(&'(') => {
+                        if last_token_was_operand | last_token_was_value {
+                            operator_stack.push((Token::Times, span(start, position)));
+                        }
+                        operator_stack.push((Token::Function(function, 0), span(start, position)));
+                    }
+                    _ => {
+                        error = Some(errors::ParseError::InvalidToken(token, Default::default()));
+                        break;
+                    }
+                }
+                last_token_was_operand = false;
+                last_token_was_value = false;
+            }
+            _ => {
+                error = Some(errors::ParseError::InvalidToken(token, Default::default()));
+                break;
+            }
+        }
+    }
+
+    if let Some(err) = error {
+        // point at the token that was being read, unless the error already points somewhere more precise
+        let end = position.clamp(start + 1, chars.len());
+        return Err(err.at(span(start, end)));
+    }
+
+    while let Some(operator) = operator_stack.pop() {
+        if let (Token::L, bracket) = operator {
+            // a `(` that was never closed
+            return Err(errors::ParseError::InvalidExpression(Default::default()).at(bracket));
+        }
+        emit(&mut output_queue, operator)?;
+    }
+    let (tokens, spans): (Vec<Token>, Vec<Range<usize>>) = output_queue.into_iter().unzip();
+    if stack_depth(&tokens, &spans)? != 1 {
+        return Err(errors::ParseError::InvalidExpression(Default::default()).at(0..input.len()));
+    }
+
+    Ok((tokens, spans))
+}
+/// Works out how many values are left on the stack once `tokens` have been evaluated, checking that no
+/// token ever runs out of values and that both sides of every jump agree.
+///
+/// An expression that compiles always leaves exactly one value, so evaluating it can't fail for lack of values.
+fn stack_depth(tokens: &[Token], spans: &[Range<usize>]) -> Result<usize, errors::ParseError> {
+    let invalid =
+        |at: usize| errors::ParseError::InvalidExpression(Default::default()).at(spans[at].clone());
+    // the body of a repeat group or rounding function is checked on its own, and always leaves one value. They are
+    // kept on a stack rather than checked by recursing, so deeply nested bodies can't run out of stack
+    struct Body {
+        end: usize,
+        /// the repeat or rounding token the body belongs to, with the depth before it and the values it pops and
+        /// pushes once the body is done
+        at: usize,
+        outer: usize,
+        pops: usize,
+        pushes: usize,
+        /// the depth expected where each jump in the body lands
+        landings: HashMap<usize, usize>,
+    }
+    let land = |body: &mut Body, at: usize, depth: usize| match body.landings.get(&at) {
+        _ if at > body.end => false,
+        None => {
+            body.landings.insert(at, depth);
+            true
+        }
+        Some(expected) => *expected == depth,
+    };
+    let mut bodies = vec![Body {
+        end: tokens.len(),
+        at: 0,
+        outer: 0,
+        pops: 0,
+        pushes: 0,
+        landings: HashMap::new(),
+    }];
+    // tokens straight after a `Jump` are only reached by jumping
+    let mut depth = Some(0);
+    let mut index = 0;
+    while let Some(body) = bodies.last_mut() {
+        if let Some(&expected) = body.landings.get(&index) {
+            match depth {
+                Some(depth) if depth != expected => return Err(invalid(index - 1)),
+                _ => depth = Some(expected),
+            }
+        }
+        if index == body.end {
+            let Some(body) = bodies.pop().filter(|_| !bodies.is_empty()) else {
+                break;
+            };
+            match depth {
+                Some(1) => {}
+                Some(_) => return Err(invalid(body.at)),
+                None => return Err(invalid(index - 1)),
+            }
+            match body.outer.checked_sub(body.pops) {
+                Some(depth_left) => depth = Some(depth_left + body.pushes),
+                None => return Err(invalid(body.at)),
+            }
+            continue;
+        }
+        let token = &tokens[index];
+        let current = depth.ok_or_else(|| invalid(index))?;
+        let at = index;
+        index += 1;
+        let (pops, pushes) = match token {
+            Token::Operand(_) | Token::Dice(_) | Token::Variable(_) => (0, 1),
+            Token::Label(_) | Token::Truthy => (1, 1),
+            Token::DynamicDice { sides, .. } => (1 + *sides as usize, 1),
+            Token::Plus
+            | Token::Minus
+            | Token::Times
+            | Token::Divide
+            | Token::Exponent
+            | Token::Compare(_) => (2, 1),
+            Token::Function(_, count) => (*count as usize, 1),
+            // the count is replaced by the list of results from evaluating the body, while the argument of a
+            // rounding function just pushes its result
+            Token::Repeat(length) | Token::Rounded(_, length) => {
+                if index + length > body.end {
+                    return Err(invalid(at));
+                }
+                let (pops, pushes) = match token {
+                    Token::Repeat(_) => (1, 1),
+                    _ => (0, 1),
+                };
+                bodies.push(Body {
+                    end: index + length,
+                    at,
+                    outer: current,
+                    pops,
+                    pushes,
+                    landings: HashMap::new(),
+                });
+                depth = Some(0);
+                continue;
+            }
+            // when they skip, `&&` and `||` replace the left hand side with their result
+            Token::And(skip) | Token::Or(skip) => {
+                if !land(body, index + skip, current) {
+                    return Err(invalid(at));
+                }
+                (1, 0)
+            }
+            Token::JumpIfFalse(skip) => {
+                if current == 0 || !land(body, index + skip, current - 1) {
+                    return Err(invalid(at));
+                }
+                (1, 0)
+            }
+            // the true branch has to leave one more value than the false branch starts with
+            Token::Jump(skip) => {
+                if body.landings.get(&index) != Some(&current.wrapping_sub(1))
+                    || !land(body, index + skip, current)
+                {
+                    return Err(invalid(at));
+                }
+                depth = None;
+                continue;
+            }
+            Token::L | Token::Negate => return Err(invalid(at)),
+        };
+        match current.checked_sub(pops) {
+            Some(depth_left) => depth = Some(depth_left + pushes),
+            None => return Err(invalid(at)),
+        }
+    }
+    depth.ok_or_else(|| invalid(index - 1))
+}
+/// Moves an operator from the operator stack to the output, filling in how far any jumps skip.
+///
+/// While on the operator stack `And`, `Or`, `JumpIfFalse` and `Jump` hold the position of their
+/// placeholder in the output instead of the distance to jump.
+fn emit(
+    output_queue: &mut Vec<(Token, Range<usize>)>,
+    operator: (Token, Range<usize>),
+) -> Result<(), errors::ParseError> {
+    let (operator, span) = operator;
+    match operator {
+        Token::And(placeholder) | Token::Or(placeholder) => {
+            output_queue.push((Token::Truthy, span));
+            let skip = output_queue.len() - placeholder - 1;
+            output_queue[placeholder].0 = match operator {
+                Token::And(_) => Token::And(skip),
+                _ => Token::Or(skip),
+            };
+        }
+        Token::Jump(placeholder) => {
+            output_queue[placeholder].0 = Token::Jump(output_queue.len() - placeholder - 1);
+        }
+        // a `?` that never found its `:`
+        Token::JumpIfFalse(_) => {
+            return Err(errors::ParseError::InvalidExpression(Default::default()).at(span))
+        }
+        Token::Negate => output_queue.push((Token::Minus, span)),
+        operator => output_queue.push((operator, span)),
+    }
+    Ok(())
+}
+/// Moves every operator that binds at least as tightly as `precedence` to the output.
+fn pop_operators(
+    operator_stack: &mut Vec<(Token, Range<usize>)>,
+    output_queue: &mut Vec<(Token, Range<usize>)>,
+    precedence: i32,
+) -> Result<(), errors::ParseError> {
+    while let Some((top, _)) = operator_stack.last() {
+        if let Token::L = top {
+            break;
+        }
+        if operator_precedence(top) < precedence {
+            break;
+        }
+        emit(output_queue, operator_stack.pop().unwrap())?;
+    }
+    Ok(())
+}
+#[inline(always)]
+fn operator_precedence(token: &Token) -> i32 {
+    match token {
+        Token::JumpIfFalse(_) | Token::Jump(_) => 0,
+        Token::Or(_) => 1,
+        Token::And(_) => 2,
+        Token::Compare(_) => 3,
+        Token::Plus | Token::Minus => 4,
+        Token::Times | Token::Divide => 5,
+        // a negation is taken before anything but an exponent, so "-2^2" is "0-(2^2)"
+        Token::Exponent | Token::Negate => 6,
+        Token::L | Token::Function(..) => 7,
+        Token::DynamicDice { .. } => 8,
+        Token::Repeat(_) | Token::Rounded(..) => 7,
+        Token::Truthy => 7,
+        // values never wait on the operator stack, but bind tighter than any operator if they did
+        Token::Operand(_) | Token::Dice(_) | Token::Variable(_) | Token::Label(_) => 9,
+    }
+}
+
+/// Rolls a die whose sides were an expression such as `2d(1d3*4)` or `2d@sides` once the sides are
+/// on the output, reading any modifiers that follow them.
+fn finish_dynamic_dice(
+    chars: &[char],
+    (offsets, ends): (&[usize], &[usize]),
+    position: &mut usize,
+    operator_stack: &mut Vec<(Token, Range<usize>)>,
+    output_queue: &mut Vec<(Token, Range<usize>)>,
+) -> Result<(), errors::ParseError> {
+    if let Some((Token::DynamicDice { .. }, _)) = operator_stack.last() {
+        if let Some((Token::DynamicDice { mut die, sides }, span)) = operator_stack.pop() {
+            parse_die_modifiers(chars, (offsets, ends), position, &mut die)?;
+            let end = ends[*position - 1];
+            output_queue.push((Token::DynamicDice { die, sides }, span.start..end));
+        }
+    }
+    Ok(())
+}
+/// Reads the digits starting at `position`, returning `None` if there are none.
+///
+/// Numbers too large to be used as an `i32` return `ParseError::Overflow`.
+fn parse_number(chars: &[char], position: &mut usize) -> Result<Option<u32>, errors::ParseError> {
+    let mut value: Option<u32> = None;
+    while let Some(digit) = chars.get(*position).and_then(|c| c.to_digit(10)) {
+        value = value
+            .unwrap_or(0)
+            .checked_mul(10)
+            .and_then(|value| value.checked_add(digit))
+            .filter(|&value| value <= i32::MAX as u32);
+        if value.is_none() {
+            return Err(errors::ParseError::Overflow(Default::default()));
+        }
+        *position += 1;
+    }
+    Ok(value)
+}
+/// Reads a whole number with an optional leading `-`, such as a face of `d{-1,0,1}` or the value of `>=-1`.
+fn parse_signed(chars: &[char], position: &mut usize) -> Result<Option<i32>, errors::ParseError> {
+    let negative = chars.get(*position) == Some(&'-');
+    if negative {
+        *position += 1;
+    }
+    Ok(parse_number(chars, position)?.map(|value| {
+        if negative {
+            -(value as i32)
+        } else {
+            value as i32
+        }
+    }))
+}
+/// Reads the sides of a die following the `d`, either a number, `%` for a d100, `F` for a Fate die
+/// or a list of faces such as `{1,1,2,3,5,8}`.
+fn parse_die_kind(chars: &[char], position: &mut usize) -> Result<DieKind, errors::ParseError> {
+    match chars.get(*position) {
+        Some('%') => {
+            *position += 1;
+            Ok(DieKind::Standard(100))
+        }
+        Some('F') => {
+            *position += 1;
+            Ok(DieKind::Fudge)
+        }
+        Some('{') => {
+            *position += 1;
+            let mut faces = Vec::new();
+            loop {
+                match parse_signed(chars, position)? {
+                    Some(face) => faces.push(face),
+                    None => return Err(errors::ParseError::InvalidDie(Default::default())),
+                }
+                *position += 1;
+                match chars.get(*position - 1) {
+                    Some(',') => {}
+                    Some('}') => break,
+                    _ => return Err(errors::ParseError::InvalidDie(Default::default())),
+                }
+            }
+            faces.sort_unstable();
+            Ok(DieKind::Custom(faces))
+        }
+        // a die needs at least one side to land on
+        _ => match parse_number(chars, position)? {
+            Some(sides) if sides > 0 => Ok(DieKind::Standard(sides)),
+            _ => Err(errors::ParseError::InvalidDie(Default::default())),
+        },
+    }
+}
+/// Reads any modifiers directly following the sides of a die such as `kh3` or `dl1`.
+///
+/// `offsets` and `ends` are the bytes each character starts and ends at, to tell where whitespace was removed.
+fn parse_die_modifiers(
+    chars: &[char],
+    (offsets, ends): (&[usize], &[usize]),
+    position: &mut usize,
+    die: &mut Die,
+) -> Result<(), errors::ParseError> {
+    // "==" and "!=" always compare the total, as do "<", ">" and "=" with whitespace before them such as
+    // "2d6 > 7", leaving "10d10>=8" to count successes
+    let compares_total = |at: usize| match (chars.get(at), chars.get(at + 1)) {
+        (Some('!' | '='), Some('=')) => true,
+        (Some('<' | '>' | '='), _) => at > 0 && offsets[at] > ends[at - 1],
+        _ => false,
+    };
+    // a die only keeps or drops once, "4d6kh3kl1" is an error pointing at the second modifier
+    let keep_again = |start: usize, end: usize| {
+        errors::ParseError::InvalidDie(errors::Location {
+            input: String::new(),
+            span: offsets[start]..ends[end - 1],
+        })
+    };
+    loop {
+        let next = chars.get(*position + 1).copied();
+        let start = *position;
+        match chars.get(*position) {
+            _ if compares_total(*position) => return Ok(()),
+            Some('k') => {
+                *position += 1;
+                let highest = match next {
+                    Some('h') => {
+                        *position += 1;
+                        true
+                    }
+                    Some('l') => {
+                        *position += 1;
+                        false
+                    }
+                    _ => true,
+                };
+                let count = parse_number(chars, position)?.unwrap_or(1);
+                if die.keep.is_some() {
+                    return Err(keep_again(start, *position));
+                }
+                die.keep = Some(if highest {
+                    Keep::Highest(count)
+                } else {
+                    Keep::Lowest(count)
+                });
+            }
+            // a `d` is only a drop modifier when it is followed by `h`, `l` or a count, otherwise
+            // it is left for the parser to treat as a new die
+            Some('d') if matches!(next, Some('h' | 'l' | '0'..='9')) => {
+                *position += 1;
+                let highest = match next {
+                    Some('h') => {
+                        *position += 1;
+                        true
+                    }
+                    Some('l') => {
+                        *position += 1;
+                        false
+                    }
+                    _ => false,
+                };
+                let count = parse_number(chars, position)?.unwrap_or(1);
+                if die.keep.is_some() {
+                    return Err(keep_again(start, *position));
+                }
+                die.keep = Some(if highest {
+                    Keep::DropHighest(count)
+                } else {
+                    Keep::DropLowest(count)
+                });
+            }
+            Some('!') => {
+                *position += 1;
+                let kind = match next {
+                    Some('!') if !compares_total(*position) => {
+                        *position += 1;
+                        ExplodeKind::Compound
+                    }
+                    Some('p') => {
+                        *position += 1;
+                        ExplodeKind::Penetrate
+                    }
+                    _ => ExplodeKind::Standard,
+                };
+                let compare = match compares_total(*position) {
+                    true => None,
+                    false => parse_compare(chars, position)?,
+                };
+                die.explode = Some(Explode { kind, compare });
+            }
+            Some('r') => {
+                *position += 1;
+                let once = next == Some('o');
+                if once {
+                    *position += 1;
+                }
+                let compare = match parse_compare(chars, position)? {
+                    Some(compare) => compare,
+                    None => return Err(errors::ParseError::InvalidDie(Default::default())),
+                };
+                // rerolling until a face that can never come up would never finish, dice with sides
+                // that are only known when rolled are checked by the reroll limit instead
+                if !once && die.every_face_matches(&compare) {
+                    return Err(errors::ParseError::RerollLimit(Default::default()));
+                }
+                die.reroll = Some(Reroll { once, compare });
+            }
+            Some('>' | '<' | '=') => die.success = parse_compare(chars, position)?,
+            Some('f') if die.success.is_some() => {
+                *position += 1;
+                match parse_compare(chars, position)? {
+                    Some(compare) => die.failure = Some(compare),
+                    None => return Err(errors::ParseError::InvalidDie(Default::default())),
+                }
+            }
+            _ => return Ok(()),
+        }
+    }
+}
+/// Reads an optional compare point such as `>8`, `<=2`, `=-1` or a bare `5` meaning equal to 5.
+fn parse_compare(
+    chars: &[char],
+    position: &mut usize,
+) -> Result<Option<Compare>, errors::ParseError> {
+    let op = match (chars.get(*position), chars.get(*position + 1)) {
+        (Some('>'), Some('=')) => CompareOp::GreaterEqual,
+        (Some('<'), Some('=')) => CompareOp::LessEqual,
+        (Some('>'), _) => CompareOp::Greater,
+        (Some('<'), _) => CompareOp::Less,
+        (Some('='), _) => CompareOp::Equal,
+        (Some('0'..='9'), _) => {
+            return Ok(parse_number(chars, position)?.map(|value| Compare {
+                op: CompareOp::Equal,
+                value: value as i32,
+            }));
+        }
+        _ => return Ok(None),
+    };
+    *position += match op {
+        CompareOp::GreaterEqual | CompareOp::LessEqual => 2,
+        _ => 1,
+    };
+    // only a compare point with an operator may be negative, "d6!-1" is an exploding die minus one
+    match parse_signed(chars, position)? {
+        Some(value) => Ok(Some(Compare { op, value })),
+        None => Err(errors::ParseError::InvalidDie(Default::default())),
+    }
+}
@@ -0,0 +1,321 @@
+use std::fmt;
+use std::ops::Range;
+
+/// Where an error was found, the equation along with the bytes of it that caused the error.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Location {
+    pub input: String,
+    pub span: Range<usize>,
+}
+/// Any error from compiling or rolling an equation.
+///
+/// `Equation::new` can only fail with a [`ParseError`] and rolling a compiled
+/// equation can only fail with an [`EvalError`], functions that do both such as
+/// `roll::roll` return this so the two can still be told apart.
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum InvalidExpressionError {
+    /// the equation could not be compiled
+    Parse(ParseError),
+    /// the equation compiled but could not be rolled
+    Evaluate(EvalError),
+}
+/// An error found while compiling an equation.
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum ParseError {
+    InvalidExpression(Location),
+    InvalidDie(Location),
+    InvalidToken(char, Location),
+    /// a die that rerolls every face it can land on
+    RerollLimit(Location),
+    /// a number too large to be stored
+    Overflow(Location),
+    /// an equation longer than its [`Limits`](crate::equation::Limits) allow
+    TooLong(Location),
+    /// parentheses nested more deeply than the limits allow
+    TooDeep(Location),
+    /// more dice than the limits allow
+    TooManyDice(Location),
+    /// a die with more sides than the limits allow
+    TooManySides(Location),
+    /// a division by a constant 0 that would fail every time the equation is rolled
+    DivideByZero(Location),
+    /// a die letter of a [`Dialect`](crate::equation::Dialect) that the notation already uses
+    InvalidDieLetter(char, Location),
+}
+/// An error found while rolling an equation that compiled.
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum EvalError {
+    InvalidExpression(Location),
+    /// a die whose number of dice or sides was worked out to be less than 1
+    InvalidDie(Location),
+    DivideByZero(Location),
+    RerollLimit(Location),
+    UnboundVariable(String, Location),
+    ListAsNumber(Location),
+    NegativeExponent(Location),
+    Overflow(Location),
+    /// more dice were rolled than the [`Limits`](crate::equation::Limits) allow
+    TooManyDice(Location),
+    /// a die was worked out to have more sides than the limits allow
+    TooManySides(Location),
+    /// a repeat group was worked out to repeat more times than the limits allow
+    TooManyRepeats(Location),
+    /// the statistics of a die would take more outcomes to work out than the limits allow
+    TooManyOutcomes(Location),
+}
+impl InvalidExpressionError {
+    /// The part of the equation that caused the error.
+    pub fn location(&self) -> &Location {
+        match self {
+            InvalidExpressionError::Parse(err) => err.location(),
+            InvalidExpressionError::Evaluate(err) => err.location(),
+        }
+    }
+}
+impl ParseError {
+    /// The part of the equation that caused the error.
+    pub fn location(&self) -> &Location {
+        match self {
+            ParseError::InvalidExpression(location)
+            | ParseError::InvalidDie(location)
+            | ParseError::InvalidToken(_, location)
+            | ParseError::RerollLimit(location)
+            | ParseError::Overflow(location)
+            | ParseError::TooLong(location)
+            | ParseError::TooDeep(location)
+            | ParseError::TooManyDice(location)
+            | ParseError::TooManySides(location)
+            | ParseError::DivideByZero(location)
+            | ParseError::InvalidDieLetter(_, location) => location,
+        }
+    }
+    fn location_mut(&mut self) -> &mut Location {
+        match self {
+            ParseError::InvalidExpression(location)
+            | ParseError::InvalidDie(location)
+            | ParseError::InvalidToken(_, location)
+            | ParseError::RerollLimit(location)
+            | ParseError::Overflow(location)
+            | ParseError::TooLong(location)
+            | ParseError::TooDeep(location)
+            | ParseError::TooManyDice(location)
+            | ParseError::TooManySides(location)
+            | ParseError::DivideByZero(location)
+            | ParseError::InvalidDieLetter(_, location) => location,
+        }
+    }
+    /// Points the error at `span`, unless it was already pointed somewhere more precise.
+    #[doc(hidden)]
+    pub fn at(mut self, span: Range<usize>) -> ParseError {
+        self.location_mut().point_at(span);
+        self
+    }
+    /// Records the equation the error was found in so it can be shown with the error.
+    #[doc(hidden)]
+    pub fn in_input(mut self, input: &str) -> ParseError {
+        self.location_mut().input = input.to_string();
+        self
+    }
+}
+impl EvalError {
+    /// The part of the equation that caused the error.
+    pub fn location(&self) -> &Location {
+        match self {
+            EvalError::InvalidExpression(location)
+            | EvalError::InvalidDie(location)
+            | EvalError::DivideByZero(location)
+            | EvalError::RerollLimit(location)
+            | EvalError::UnboundVariable(_, location)
+            | EvalError::ListAsNumber(location)
+            | EvalError::NegativeExponent(location)
+            | EvalError::Overflow(location)
+            | EvalError::TooManyDice(location)
+            | EvalError::TooManySides(location)
+            | EvalError::TooManyRepeats(location)
+            | EvalError::TooManyOutcomes(location) => location,
+        }
+    }
+    fn location_mut(&mut self) -> &mut Location {
+        match self {
+            EvalError::InvalidExpression(location)
+            | EvalError::InvalidDie(location)
+            | EvalError::DivideByZero(location)
+            | EvalError::RerollLimit(location)
+            | EvalError::UnboundVariable(_, location)
+            | EvalError::ListAsNumber(location)
+            | EvalError::NegativeExponent(location)
+            | EvalError::Overflow(location)
+            | EvalError::TooManyDice(location)
+            | EvalError::TooManySides(location)
+            | EvalError::TooManyRepeats(location)
+            | EvalError::TooManyOutcomes(location) => location,
+        }
+    }
+    /// Points the error at `span`, unless it was already pointed somewhere more precise.
+    #[doc(hidden)]
+    pub fn at(mut self, span: Range<usize>) -> EvalError {
+        self.location_mut().point_at(span);
+        self
+    }
+    /// Records the equation the error was found in so it can be shown with the error.
+    #[doc(hidden)]
+    pub fn in_input(mut self, input: &str) -> EvalError {
+        self.location_mut().input = input.to_string();
+        self
+    }
+}
+impl Location {
+    fn point_at(&mut self, span: Range<usize>) {
+        if self.span == (0..0) {
+            self.span = span;
+        }
+    }
+    /// Writes the equation on a new line with the part that caused the error underlined.
+    fn underline(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.input.is_empty() {
+            return Ok(());
+        }
+        // count characters rather than bytes so the caret lines up under multi byte characters
+        let column = self
+            .input
+            .get(..self.span.start)
+            .map_or(0, |before| before.chars().count());
+        let width = self
+            .input
+            .get(self.span.clone())
+            .map_or(1, |text| text.chars().count().max(1));
+        write!(
+            f,
+            "\n{}\n{}^{}",
+            self.input,
+            " ".repeat(column),
+            "~".repeat(width - 1)
+        )
+    }
+}
+impl From<ParseError> for InvalidExpressionError {
+    fn from(err: ParseError) -> InvalidExpressionError {
+        InvalidExpressionError::Parse(err)
+    }
+}
+impl From<EvalError> for InvalidExpressionError {
+    fn from(err: EvalError) -> InvalidExpressionError {
+        InvalidExpressionError::Evaluate(err)
+    }
+}
+
+/// Shows the error message followed by the equation with the part that caused it underlined, such as
+///
+/// ```text
+/// Error: Attempted to divide by 0
+/// 1d6 / (2 - 2)
+///     ^
+/// ```
+impl fmt::Display for InvalidExpressionError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            InvalidExpressionError::Parse(err) => err.fmt(f),
+            InvalidExpressionError::Evaluate(err) => err.fmt(f),
+        }
+    }
+}
+impl fmt::Display for ParseError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ParseError::InvalidExpression(_) => write!(
+                f,
+                "Error: The expression could not be parsed, incorrect format?"
+            ),
+            ParseError::InvalidDie(_) => {
+                write!(f, "Error: Die expression could not be parsed.")
+            }
+            ParseError::InvalidToken(t, _) => {
+                write!(f, "Error: Unexpected token \'{}\' found while parsing", t)
+            }
+            ParseError::RerollLimit(_) => {
+                write!(
+                    f,
+                    "Error: Die could not stop rerolling within the reroll limit"
+                )
+            }
+            ParseError::Overflow(_) => {
+                write!(f, "Error: The result was too large to be stored")
+            }
+            ParseError::TooLong(_) => write!(f, "Error: The expression is too long"),
+            ParseError::TooDeep(_) => {
+                write!(f, "Error: Parentheses are nested too deeply")
+            }
+            ParseError::TooManyDice(_) => write!(f, "Error: Too many dice to roll"),
+            ParseError::TooManySides(_) => write!(f, "Error: Die has too many sides"),
+            ParseError::DivideByZero(_) => write!(f, "Error: Attempted to divide by 0"),
+            ParseError::InvalidDieLetter(letter, _) => write!(
+                f,
+                "Error: \'{}\' can\'t be a die letter, it is already used by the notation",
+                letter
+            ),
+        }?;
+        self.location().underline(f)
+    }
+}
+impl fmt::Display for EvalError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            EvalError::InvalidExpression(_) => write!(
+                f,
+                "Error: The expression could not be parsed, incorrect format?"
+            ),
+            EvalError::InvalidDie(_) => {
+                write!(f, "Error: Die expression could not be parsed.")
+            }
+            EvalError::DivideByZero(_) => {
+                write!(f, "Error: Attempted to divide by 0")
+            }
+            EvalError::RerollLimit(_) => {
+                write!(
+                    f,
+                    "Error: Die could not stop rerolling within the reroll limit"
+                )
+            }
+            EvalError::ListAsNumber(_) => {
+                write!(
+                    f,
+                    "Error: A repeated roll gives a list of results and can only be used inside sum(), min() or max()"
+                )
+            }
+            EvalError::NegativeExponent(_) => {
+                write!(
+                    f,
+                    "Error: A whole number can't be raised to a negative exponent, use roll_f64() for fractions"
+                )
+            }
+            EvalError::Overflow(_) => {
+                write!(f, "Error: The result was too large to be stored")
+            }
+            EvalError::TooManyDice(_) => write!(f, "Error: Too many dice to roll"),
+            EvalError::TooManySides(_) => write!(f, "Error: Die has too many sides"),
+            EvalError::TooManyRepeats(_) => {
+                write!(f, "Error: Too many repeats to roll")
+            }
+            EvalError::TooManyOutcomes(_) => {
+                write!(
+                    f,
+                    "Error: Die has too many outcomes to work out its statistics"
+                )
+            }
+            EvalError::UnboundVariable(name, _) => {
+                write!(
+                    f,
+                    "Error: No value was given for the variable \'@{}\'",
+                    name
+                )
+            }
+        }?;
+        self.location().underline(f)
+    }
+}
+impl std::error::Error for InvalidExpressionError {}
+impl std::error::Error for ParseError {}
+impl std::error::Error for EvalError {}
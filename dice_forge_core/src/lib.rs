@@ -0,0 +1,6 @@
+//! The parser and compiler of [dice_forge](https://crates.io/crates/dice_forge), shared with its `dice!` macro
+//! so the macro compiles equations exactly as `Equation::new` does.
+//!
+//! Use dice_forge rather than this crate, it re-exports everything here that is meant to be used.
+pub mod equation;
+pub mod errors;
@@ -0,0 +1,168 @@
+//! The `dice!` macro for [dice_forge](https://crates.io/crates/dice_forge), enabled by its `macros` feature.
+//!
+//! `dice!("3d6+2")` compiles the equation while the program is built, so a typo is a compile error pointing
+//! at the equation rather than an error on the first roll, and expands to a ready made `Equation` that
+//! doesn't have to be parsed when the program runs.
+
+use dice_forge_core::equation::{compile, Compare, Dialect, Die, DieKind, Keep, Limits, Token};
+use proc_macro2::{Span, TokenStream};
+use quote::{format_ident, quote};
+use std::fmt::Debug;
+use std::ops::Range;
+use syn::{parse_macro_input, LitStr};
+
+/// Compiles a dice equation while the program is built, giving an `Equation` the same as
+/// `Equation::new(..).unwrap()` would.
+///
+/// An equation that can't be compiled is a compile error showing where the problem is.
+#[proc_macro]
+pub fn dice(input: proc_macro::TokenStream) -> proc_macro::TokenStream {
+    let literal = parse_macro_input!(input as LitStr);
+    let input = literal.value();
+    // compiled the same as `Equation::new`, which uses the default dialect and limits
+    match compile(&input, &Dialect::default(), &Limits::default()) {
+        Ok((tokens, spans)) => {
+            let tokens = tokens.iter().map(token);
+            let spans = spans.iter().map(|span| {
+                let (start, end) = (span.start, span.end);
+                quote!(#start..#end)
+            });
+            quote! {
+                ::dice_forge::Equation::__from_compiled(
+                    ::std::vec![#(#tokens),*],
+                    ::std::vec![#(#spans),*],
+                    #input,
+                )
+            }
+            .into()
+        }
+        Err(err) => {
+            let span = subspan(&literal, &err.location().span);
+            syn::Error::new(span, err).to_compile_error().into()
+        }
+    }
+}
+/// Where the bytes `span` of the value of `literal` were written, falling back to the whole literal when the string
+/// has escapes, an error isn't about any part of it or the compiler can't point inside of a literal.
+fn subspan(literal: &LitStr, span: &Range<usize>) -> Span {
+    let token = literal.token();
+    let source = token.to_string();
+    let value = literal.value();
+    // the value starts after the `"` along with any `r#` of a raw string
+    let start = match source.find('"') {
+        Some(quote) if source.get(quote + 1..quote + 1 + value.len()) == Some(&value[..]) => {
+            quote + 1
+        }
+        _ => return literal.span(),
+    };
+    if span.is_empty() || span.end > value.len() {
+        return literal.span();
+    }
+    token
+        .subspan(start + span.start..start + span.end)
+        .unwrap_or_else(|| literal.span())
+}
+/// The code building `token` in dice_forge.
+fn token(token: &Token) -> TokenStream {
+    let path = quote!(::dice_forge::__private::Token);
+    match token {
+        Token::Operand(value) => quote!(#path::Operand(#value)),
+        Token::Plus => quote!(#path::Plus),
+        Token::Minus => quote!(#path::Minus),
+        Token::Times => quote!(#path::Times),
+        Token::Divide => quote!(#path::Divide),
+        Token::Exponent => quote!(#path::Exponent),
+        Token::L => quote!(#path::L),
+        Token::Negate => quote!(#path::Negate),
+        Token::Truthy => quote!(#path::Truthy),
+        Token::Dice(dice) => {
+            let dice = die(dice);
+            quote!(#path::Dice(#dice))
+        }
+        Token::DynamicDice { die: dice, sides } => {
+            let dice = die(dice);
+            quote!(#path::DynamicDice { die: #dice, sides: #sides })
+        }
+        Token::Variable(name) => quote!(#path::Variable(::std::string::String::from(#name))),
+        Token::Label(label) => quote!(#path::Label(::std::string::String::from(#label))),
+        Token::Function(function, count) => {
+            let function = variant("Function", function);
+            quote!(#path::Function(#function, #count))
+        }
+        Token::Rounded(rounding, length) => {
+            let rounding = variant("RoundingPolicy", rounding);
+            quote!(#path::Rounded(#rounding, #length))
+        }
+        Token::Compare(op) => {
+            let op = variant("CompareOp", op);
+            quote!(#path::Compare(#op))
+        }
+        Token::Repeat(length) => quote!(#path::Repeat(#length)),
+        Token::And(skip) => quote!(#path::And(#skip)),
+        Token::Or(skip) => quote!(#path::Or(#skip)),
+        Token::JumpIfFalse(skip) => quote!(#path::JumpIfFalse(#skip)),
+        Token::Jump(skip) => quote!(#path::Jump(#skip)),
+    }
+}
+fn die(die: &Die) -> TokenStream {
+    let number = die.number;
+    let kind = match &die.kind {
+        DieKind::Standard(sides) => quote!(::dice_forge::__private::DieKind::Standard(#sides)),
+        DieKind::Fudge => quote!(::dice_forge::__private::DieKind::Fudge),
+        DieKind::Custom(faces) => {
+            quote!(::dice_forge::__private::DieKind::Custom(
+                ::std::vec![#(#faces),*]
+            ))
+        }
+    };
+    let keep = option(die.keep.map(|keep| {
+        let (name, count) = match keep {
+            Keep::Highest(count) => ("Highest", count),
+            Keep::Lowest(count) => ("Lowest", count),
+            Keep::DropHighest(count) => ("DropHighest", count),
+            Keep::DropLowest(count) => ("DropLowest", count),
+        };
+        let name = format_ident!("{}", name);
+        quote!(::dice_forge::equation::Keep::#name(#count))
+    }));
+    let explode = option(die.explode.map(|explode| {
+        let kind = variant("ExplodeKind", &explode.kind);
+        let compare = option(explode.compare.map(compare));
+        quote!(::dice_forge::equation::Explode { kind: #kind, compare: #compare })
+    }));
+    let reroll = option(die.reroll.map(|reroll| {
+        let once = reroll.once;
+        let compare = compare(reroll.compare);
+        quote!(::dice_forge::equation::Reroll { once: #once, compare: #compare })
+    }));
+    let success = option(die.success.map(compare));
+    let failure = option(die.failure.map(compare));
+    quote! {
+        ::dice_forge::__private::Die {
+            number: #number,
+            kind: #kind,
+            keep: #keep,
+            explode: #explode,
+            reroll: #reroll,
+            success: #success,
+            failure: #failure,
+        }
+    }
+}
+fn compare(compare: Compare) -> TokenStream {
+    let op = variant("CompareOp", &compare.op);
+    let value = compare.value;
+    quote!(::dice_forge::equation::Compare { op: #op, value: #value })
+}
+fn option(value: Option<TokenStream>) -> TokenStream {
+    match value {
+        Some(value) => quote!(::std::option::Option::Some(#value)),
+        None => quote!(::std::option::Option::None),
+    }
+}
+/// A variant of one of the public enums without any fields, which is named the same as its `Debug` output.
+fn variant(ty: &str, value: &impl Debug) -> TokenStream {
+    let ty = format_ident!("{}", ty);
+    let value = format_ident!("{}", format!("{:?}", value));
+    quote!(::dice_forge::equation::#ty::#value)
+}
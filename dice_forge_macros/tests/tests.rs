@@ -0,0 +1,43 @@
+#[cfg(test)]
+mod tests {
+    use dice_forge::Equation;
+    use dice_forge_macros::dice;
+    use std::collections::HashMap;
+    #[test]
+    fn same_as_new() {
+        let equation = dice!("3d6+2");
+        assert_eq!(Equation::new("3d6+2").unwrap(), equation);
+        assert_eq!((5, 20), equation.range().unwrap());
+        assert_eq!("3d6 + 2", equation.to_string());
+        let input =
+            "4d6kh3 + 2d20kl1!>19r<2 + 10d10>=8f1 + 4dF + d{1,1,2,3,5,8} + 1d10!!ro1 + 2d6!p";
+        assert_eq!(
+            Equation::new(input).unwrap(),
+            dice!(
+                "4d6kh3 + 2d20kl1!>19r<2 + 10d10>=8f1 + 4dF + d{1,1,2,3,5,8} + 1d10!!ro1 + 2d6!p"
+            )
+        );
+        let input = "(1d4)d(2d3*4) + floor(@level/2) + max(1, 1d6-2) + (1d20+5 >= 15 ? 2d6+3 : 0) + (1 && 0 || 1)";
+        assert_eq!(Equation::new(input).unwrap(), dice!("(1d4)d(2d3*4) + floor(@level/2) + max(1, 1d6-2) + (1d20+5 >= 15 ? 2d6+3 : 0) + (1 && 0 || 1)"));
+    }
+    #[test]
+    fn rolls() {
+        let attack = dice!("1d20 + @str_mod + @prof");
+        assert_eq!(vec!["str_mod", "prof"], attack.variables());
+        let vars = HashMap::from([("str_mod", 3), ("prof", 2)]);
+        assert_eq!((6, 25), attack.range_with_vars(&vars).unwrap());
+        let stats = dice!("6x(4d6kh3)").roll_many().unwrap();
+        assert_eq!(6, stats.len());
+        let labeled = dice!("1d8[slashing] + 2d6[fire] + 3")
+            .roll_labeled()
+            .unwrap();
+        assert_eq!(2, labeled.labels.len());
+        match dice!("1d6/(1d1-1)").roll() {
+            Err(e) => assert_eq!(
+                "Error: Attempted to divide by 0\n1d6/(1d1-1)\n   ^",
+                e.to_string()
+            ),
+            Ok(_) => panic!(),
+        }
+    }
+}
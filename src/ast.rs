@@ -0,0 +1,804 @@
+//! A typed tree of an equation, for tools that need to inspect, change or show an equation rather than roll it.
+//!
+//! [`Equation::ast`](crate::Equation::ast) gives the tree of a compiled equation and
+//! [`Equation::from_ast`](crate::Equation::from_ast) compiles a tree back into an equation. A tree can be walked
+//! with a [`Visitor`] or rebuilt with a [`Fold`], and is shown in the same notation it is parsed from.
+//!
+//! # Example
+//!
+//! ```
+//! use dice_forge::ast::{Expr, Visitor};
+//! use dice_forge::Equation;
+//!
+//! // counts every die an equation rolls
+//! struct CountDice(u32);
+//! impl Visitor for CountDice {
+//!     fn visit_expr(&mut self, expr: &Expr) {
+//!         if let Expr::Dice(dice) = expr {
+//!             if let Expr::Number(count) = *dice.count {
+//!                 self.0 += count;
+//!             }
+//!         }
+//!         dice_forge::ast::walk_expr(self, expr);
+//!     }
+//! }
+//!
+//! let mut counter = CountDice(0);
+//! counter.visit_expr(&Equation::new("4d6kh3 + 2d8").unwrap().ast());
+//! assert_eq!(6, counter.0);
+//! ```
+use crate::equation::{
+    Compare, CompareOp, Explode, ExplodeKind, Function, Keep, Reroll, RoundingPolicy,
+};
+use dice_forge_core::equation::{Die, DieKind, Token};
+use std::collections::HashMap;
+use std::fmt;
+use std::ops::Range;
+
+/// A part of an equation.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub enum Expr {
+    /// a number written in the equation, negative numbers are written as `0 - n`
+    Number(u32),
+    /// a variable such as `@str_mod`, without the `@`
+    Variable(String),
+    Dice(Dice),
+    Binary {
+        op: BinaryOp,
+        lhs: Box<Expr>,
+        rhs: Box<Expr>,
+    },
+    /// `condition ? then : otherwise`
+    Conditional {
+        condition: Box<Expr>,
+        then: Box<Expr>,
+        otherwise: Box<Expr>,
+    },
+    /// a call to a built in function such as `max(1, 1d6-2)`
+    Call {
+        function: Function,
+        args: Vec<Expr>,
+    },
+    /// `count x (body)`, rolling `body` `count` times
+    Repeat {
+        count: Box<Expr>,
+        body: Box<Expr>,
+    },
+    /// an expression followed by a label such as `1d8[slashing]`
+    Label {
+        expr: Box<Expr>,
+        label: String,
+    },
+    /// an expression that was wrapped in parentheses
+    Group(Box<Expr>),
+}
+/// An operator between two expressions.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum BinaryOp {
+    Add,
+    Subtract,
+    Multiply,
+    Divide,
+    Power,
+    Compare(CompareOp),
+    And,
+    Or,
+}
+/// Dice to roll along with their modifiers, such as `4d6kh3` or `(@level)d6!`.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct Dice {
+    /// how many dice are rolled, a `d6` on its own rolls `Number(1)`
+    pub count: Box<Expr>,
+    pub sides: Sides,
+    pub keep: Option<Keep>,
+    pub explode: Option<Explode>,
+    pub reroll: Option<Reroll>,
+    /// the compare point that turns the dice into a success counting pool
+    pub success: Option<Compare>,
+    /// the compare point for failures subtracted from a success counting pool
+    pub failure: Option<Compare>,
+}
+/// The faces a die can land on.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub enum Sides {
+    /// faces numbered 1 through the number, `d%` is `Number(100)`
+    Number(u32),
+    /// Fate dice with the faces -1, 0 and +1
+    Fudge,
+    /// every face listed, sorted from lowest to highest
+    Custom(Vec<i32>),
+    /// faces numbered 1 through the result of an expression such as `2d(1d3*4)`
+    Expr(Box<Expr>),
+}
+/// Walks a tree of expressions without changing it.
+///
+/// Every method walks into the children of what it visits by default, so an implementation only
+/// needs to override the methods for the parts it is interested in and call [`walk_expr`] or
+/// [`walk_dice`] to keep walking.
+pub trait Visitor {
+    fn visit_expr(&mut self, expr: &Expr) {
+        walk_expr(self, expr);
+    }
+    fn visit_dice(&mut self, dice: &Dice) {
+        walk_dice(self, dice);
+    }
+}
+/// Visits every child of `expr`.
+pub fn walk_expr<V: Visitor + ?Sized>(visitor: &mut V, expr: &Expr) {
+    match expr {
+        Expr::Number(_) | Expr::Variable(_) => {}
+        Expr::Dice(dice) => visitor.visit_dice(dice),
+        Expr::Binary { lhs, rhs, .. } => {
+            visitor.visit_expr(lhs);
+            visitor.visit_expr(rhs);
+        }
+        Expr::Conditional {
+            condition,
+            then,
+            otherwise,
+        } => {
+            visitor.visit_expr(condition);
+            visitor.visit_expr(then);
+            visitor.visit_expr(otherwise);
+        }
+        Expr::Call { args, .. } => args.iter().for_each(|arg| visitor.visit_expr(arg)),
+        Expr::Repeat { count, body } => {
+            visitor.visit_expr(count);
+            visitor.visit_expr(body);
+        }
+        Expr::Label { expr, .. } | Expr::Group(expr) => visitor.visit_expr(expr),
+    }
+}
+/// Visits the expressions giving the number of dice and their sides.
+pub fn walk_dice<V: Visitor + ?Sized>(visitor: &mut V, dice: &Dice) {
+    visitor.visit_expr(&dice.count);
+    if let Sides::Expr(sides) = &dice.sides {
+        visitor.visit_expr(sides);
+    }
+}
+/// Rebuilds a tree of expressions, replacing the parts it is interested in.
+///
+/// Every method rebuilds the children of what it is given by default, an implementation calls
+/// [`fold_children`] or [`fold_dice_children`] to keep going into the parts it overrides.
+///
+/// # Example
+///
+/// ```
+/// use dice_forge::ast::{fold_children, Expr, Fold};
+/// use dice_forge::Equation;
+///
+/// // replaces every variable with 0
+/// struct Unbind;
+/// impl Fold for Unbind {
+///     fn fold_expr(&mut self, expr: Expr) -> Expr {
+///         match expr {
+///             Expr::Variable(_) => Expr::Number(0),
+///             expr => fold_children(self, expr),
+///         }
+///     }
+/// }
+///
+/// let ast = Unbind.fold_expr(Equation::new("1d20 + @str_mod").unwrap().ast());
+/// assert_eq!("1d20 + 0", ast.to_string());
+/// ```
+pub trait Fold {
+    fn fold_expr(&mut self, expr: Expr) -> Expr {
+        fold_children(self, expr)
+    }
+    fn fold_dice(&mut self, dice: Dice) -> Dice {
+        fold_dice_children(self, dice)
+    }
+}
+/// Folds every child of `expr`.
+pub fn fold_children<F: Fold + ?Sized>(folder: &mut F, expr: Expr) -> Expr {
+    let mut fold = |expr: Box<Expr>| Box::new(folder.fold_expr(*expr));
+    match expr {
+        Expr::Number(_) | Expr::Variable(_) => expr,
+        Expr::Dice(dice) => Expr::Dice(folder.fold_dice(dice)),
+        Expr::Binary { op, lhs, rhs } => Expr::Binary {
+            op,
+            lhs: fold(lhs),
+            rhs: fold(rhs),
+        },
+        Expr::Conditional {
+            condition,
+            then,
+            otherwise,
+        } => Expr::Conditional {
+            condition: fold(condition),
+            then: fold(then),
+            otherwise: fold(otherwise),
+        },
+        Expr::Call { function, args } => Expr::Call {
+            function,
+            args: args.into_iter().map(|arg| folder.fold_expr(arg)).collect(),
+        },
+        Expr::Repeat { count, body } => Expr::Repeat {
+            count: fold(count),
+            body: fold(body),
+        },
+        Expr::Label { expr, label } => Expr::Label {
+            expr: fold(expr),
+            label,
+        },
+        Expr::Group(expr) => Expr::Group(fold(expr)),
+    }
+}
+/// Folds the expressions giving the number of dice and their sides.
+pub fn fold_dice_children<F: Fold + ?Sized>(folder: &mut F, dice: Dice) -> Dice {
+    Dice {
+        count: Box::new(folder.fold_expr(*dice.count)),
+        sides: match dice.sides {
+            Sides::Expr(sides) => Sides::Expr(Box::new(folder.fold_expr(*sides))),
+            sides => sides,
+        },
+        ..dice
+    }
+}
+
+impl Expr {
+    /// Removes every group, the expression is still shown with the parentheses it needs.
+    ///
+    /// A number of dice in parentheses such as "(2)d6" keeps one group, as it is worked out when rolling rather
+    /// than being part of the die.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use dice_forge::Equation;
+    ///
+    /// let ast = Equation::new("((2)) * (3 + 4)").unwrap().ast();
+    /// assert_eq!("((2))*(3+4)", ast.to_string());
+    /// assert_eq!("2*(3+4)", ast.without_groups().to_string());
+    /// ```
+    pub fn without_groups(self) -> Expr {
+        struct Ungroup;
+        impl Fold for Ungroup {
+            fn fold_expr(&mut self, expr: Expr) -> Expr {
+                match expr {
+                    Expr::Group(expr) => self.fold_expr(*expr),
+                    expr => fold_children(self, expr),
+                }
+            }
+            fn fold_dice(&mut self, dice: Dice) -> Dice {
+                let grouped = matches!(*dice.count, Expr::Group(_));
+                let mut dice = fold_dice_children(self, dice);
+                if grouped && matches!(*dice.count, Expr::Number(_)) {
+                    dice.count = Box::new(Expr::Group(dice.count));
+                }
+                dice
+            }
+        }
+        Ungroup.fold_expr(self)
+    }
+    /// How tightly the expression binds, parts of an expression that bind less tightly than the operator they
+    /// are used with need parentheses.
+    fn precedence(&self) -> u8 {
+        match self {
+            Expr::Conditional { .. } => 0,
+            Expr::Binary { op, .. } => op.precedence(),
+            _ => 9,
+        }
+    }
+    /// Checks if the expression is shown ending with a die, which would read a following compare as a
+    /// compare point of the die. Groups are looked through when they aren't shown.
+    fn ends_with_dice(&self, groups: bool) -> bool {
+        let mut expr = self;
+        loop {
+            expr = match expr {
+                Expr::Dice(_) => return true,
+                Expr::Binary { rhs, .. } => rhs,
+                Expr::Conditional { otherwise, .. } => otherwise,
+                Expr::Group(inner) if !groups => inner,
+                _ => return false,
+            };
+        }
+    }
+}
+/// A part of an expression that is still to be shown.
+enum Step<'a> {
+    /// an expression and whether it is nested inside another expression
+    Expr(&'a Expr, bool),
+    /// an expression shown as part of a larger expression, in parentheses when it binds less tightly than the
+    /// precedence or when forced to
+    Operand(&'a Expr, u8, bool, bool),
+    /// the count of dice or repeats, which can only be a number or something in parentheses
+    Count(&'a Expr),
+    Dice(&'a Dice),
+    Sides(&'a Sides),
+    Modifiers(&'a Dice),
+    Text(&'a str),
+}
+/// Shows the expression, only the outermost operators of an equation are spaced out so that
+/// "3 + 1d6*(4+3^2)" is easy to read. Groups are left out unless `groups` is set.
+///
+/// The parts still to be shown are kept on a stack rather than shown by recursion, so that deeply nested
+/// equations can't overflow the call stack.
+fn write<'a>(f: &mut fmt::Formatter<'_>, step: Step<'a>, groups: bool) -> fmt::Result {
+    let ungroup = |mut expr: &'a Expr| {
+        while let (Expr::Group(inner), false) = (expr, groups) {
+            expr = inner;
+        }
+        expr
+    };
+    // the parts of an expression are pushed last first, so that they are shown in order
+    let mut steps = vec![step];
+    while let Some(step) = steps.pop() {
+        match step {
+            Step::Text(text) => f.write_str(text)?,
+            Step::Count(count) => match ungroup(count) {
+                count @ (Expr::Number(_) | Expr::Group(_)) => steps.push(Step::Expr(count, true)),
+                count => steps.push(Step::Operand(count, u8::MAX, true, true)),
+            },
+            Step::Operand(expr, precedence, nested, force) => {
+                let expr = ungroup(expr);
+                if force || expr.precedence() < precedence {
+                    steps.extend([Step::Text(")"), Step::Expr(expr, true), Step::Text("(")]);
+                } else {
+                    steps.push(Step::Expr(expr, nested));
+                }
+            }
+            Step::Dice(dice) => {
+                steps.extend([
+                    Step::Modifiers(dice),
+                    Step::Sides(&dice.sides),
+                    Step::Text("d"),
+                ]);
+                // "(2)d6" works out its number of dice when rolling, so it keeps a pair of parentheses to
+                // compile back into the same equation
+                match (&*dice.count, ungroup(&dice.count)) {
+                    (Expr::Group(_), count @ Expr::Number(_)) => {
+                        steps.extend([Step::Text(")"), Step::Expr(count, true), Step::Text("(")])
+                    }
+                    _ => steps.push(Step::Count(&dice.count)),
+                }
+            }
+            Step::Sides(sides) => match sides {
+                Sides::Number(sides) => write!(f, "{}", sides)?,
+                Sides::Fudge => write!(f, "F")?,
+                Sides::Custom(faces) => {
+                    let faces: Vec<String> = faces.iter().map(|face| face.to_string()).collect();
+                    write!(f, "{{{}}}", faces.join(","))?;
+                }
+                // a variable would run on into any modifiers, so the sides are always in parentheses
+                Sides::Expr(sides) => steps.push(Step::Operand(sides, u8::MAX, true, true)),
+            },
+            Step::Modifiers(dice) => dice.write_modifiers(f)?,
+            Step::Expr(expr, nested) => match ungroup(expr) {
+                Expr::Number(value) => write!(f, "{}", value)?,
+                Expr::Variable(name) => write!(f, "@{}", name)?,
+                Expr::Dice(dice) => steps.push(Step::Dice(dice)),
+                Expr::Binary { op, lhs, rhs } => {
+                    let precedence = op.precedence();
+                    // exponents are right associative, everything else is left associative
+                    let (left, right) = match op {
+                        BinaryOp::Power => (precedence + 1, precedence),
+                        _ => (precedence, precedence + 1),
+                    };
+                    let force = matches!(op, BinaryOp::Compare(_)) && lhs.ends_with_dice(groups);
+                    let space = if nested || precedence > 4 { "" } else { " " };
+                    steps.extend([
+                        Step::Operand(rhs, right, nested, false),
+                        Step::Text(space),
+                        Step::Text(op.symbol()),
+                        Step::Text(space),
+                        Step::Operand(lhs, left, nested, force),
+                    ]);
+                }
+                Expr::Conditional {
+                    condition,
+                    then,
+                    otherwise,
+                } => {
+                    let (question, colon) = if nested { ("?", ":") } else { (" ? ", " : ") };
+                    steps.extend([
+                        Step::Operand(otherwise, 0, nested, false),
+                        Step::Text(colon),
+                        Step::Operand(then, 0, nested, false),
+                        Step::Text(question),
+                        Step::Operand(condition, 1, nested, false),
+                    ]);
+                }
+                Expr::Call { function, args } => {
+                    steps.push(Step::Text(")"));
+                    for (n, arg) in args.iter().enumerate().rev() {
+                        steps.push(Step::Expr(arg, true));
+                        if n > 0 {
+                            steps.push(Step::Text(", "));
+                        }
+                    }
+                    steps.extend([Step::Text("("), Step::Text(function.name())]);
+                }
+                Expr::Repeat { count, body } => steps.extend([
+                    Step::Text(")"),
+                    Step::Expr(body, true),
+                    Step::Text("x("),
+                    Step::Count(count),
+                ]),
+                Expr::Label { expr, label } => steps.extend([
+                    Step::Text("]"),
+                    Step::Text(label),
+                    Step::Text("["),
+                    Step::Operand(expr, 9, nested, false),
+                ]),
+                Expr::Group(expr) => {
+                    steps.extend([Step::Text(")"), Step::Expr(expr, true), Step::Text("(")])
+                }
+            },
+        }
+    }
+    Ok(())
+}
+/// Shows the expression as [`Expr::without_groups`] would without taking the tree apart, for showing an
+/// equation.
+pub(crate) fn write_without_groups(f: &mut fmt::Formatter<'_>, expr: &Expr) -> fmt::Result {
+    write(f, Step::Expr(expr, false), false)
+}
+impl BinaryOp {
+    fn precedence(&self) -> u8 {
+        match self {
+            BinaryOp::Or => 1,
+            BinaryOp::And => 2,
+            BinaryOp::Compare(_) => 3,
+            BinaryOp::Add | BinaryOp::Subtract => 4,
+            BinaryOp::Multiply | BinaryOp::Divide => 5,
+            BinaryOp::Power => 6,
+        }
+    }
+    fn symbol(&self) -> &'static str {
+        match self {
+            BinaryOp::Add => "+",
+            BinaryOp::Subtract => "-",
+            BinaryOp::Multiply => "*",
+            BinaryOp::Divide => "/",
+            BinaryOp::Power => "^",
+            BinaryOp::Compare(CompareOp::Equal) => "==",
+            BinaryOp::Compare(op) => compare_symbol(*op),
+            BinaryOp::And => "&&",
+            BinaryOp::Or => "||",
+        }
+    }
+}
+/// The symbol of a compare operator, with `Equal` shown as the `=` of a compare point.
+fn compare_symbol(op: CompareOp) -> &'static str {
+    match op {
+        CompareOp::Equal => "=",
+        CompareOp::NotEqual => "!=",
+        CompareOp::Greater => ">",
+        CompareOp::GreaterEqual => ">=",
+        CompareOp::Less => "<",
+        CompareOp::LessEqual => "<=",
+    }
+}
+impl Dice {
+    /// Shows everything after the sides of the dice.
+    fn write_modifiers(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.keep {
+            Some(Keep::Highest(n)) => write!(f, "kh{}", n)?,
+            Some(Keep::Lowest(n)) => write!(f, "kl{}", n)?,
+            Some(Keep::DropHighest(n)) => write!(f, "dh{}", n)?,
+            Some(Keep::DropLowest(n)) => write!(f, "dl{}", n)?,
+            None => {}
+        }
+        // a success compare point is only read after a die when it starts with `<`, `>` or `=`, and comes
+        // before any explosion so that it isn't read as the compare point of the explosion
+        write_compare_point(f, self.success, true)?;
+        if self.failure.is_some() {
+            write!(f, "f")?;
+            write_compare_point(f, self.failure, false)?;
+        }
+        if let Some(explode) = self.explode {
+            let kind = match explode.kind {
+                ExplodeKind::Standard => "!",
+                ExplodeKind::Compound => "!!",
+                ExplodeKind::Penetrate => "!p",
+            };
+            write!(f, "{}", kind)?;
+            write_compare_point(f, explode.compare, false)?;
+        }
+        if let Some(reroll) = self.reroll {
+            write!(f, "{}", if reroll.once { "ro" } else { "r" })?;
+            write_compare_point(f, Some(reroll.compare), false)?;
+        }
+        Ok(())
+    }
+}
+/// Shows a compare point of a die, where an equal compare point is usually written as just the number.
+fn write_compare_point(
+    f: &mut fmt::Formatter<'_>,
+    compare: Option<Compare>,
+    explicit: bool,
+) -> fmt::Result {
+    match compare {
+        Some(Compare {
+            op: CompareOp::Equal,
+            value,
+        }) if !explicit => write!(f, "{}", value),
+        Some(Compare { op, value }) => write!(f, "{}{}", compare_symbol(op), value),
+        None => Ok(()),
+    }
+}
+/// Shows the expression in the notation it is parsed from, parentheses are added wherever they are
+/// needed for it to be parsed back into the same expression.
+impl fmt::Display for Expr {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write(f, Step::Expr(self, false), true)
+    }
+}
+impl fmt::Display for Dice {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write(f, Step::Dice(self), true)
+    }
+}
+
+/// Rebuilds the tree of an equation from its compiled tokens.
+///
+/// Parentheses don't appear in the tokens, so the byte ranges of the tokens are used to find the
+/// parentheses in `input` that were written around each part of the equation.
+pub(crate) fn from_postfix(tokens: &[Token], spans: &[Range<usize>], input: &str) -> Option<Expr> {
+    let builder = Builder {
+        tokens,
+        spans,
+        input,
+        brackets: matching_brackets(input),
+    };
+    builder.build(0..tokens.len()).map(|(expr, _)| expr)
+}
+/// The byte of the `)` closing each `(` in `input`, ignoring any inside of a label.
+fn matching_brackets(input: &str) -> HashMap<usize, usize> {
+    let mut brackets = HashMap::new();
+    let mut open = Vec::new();
+    let mut in_label = false;
+    for (at, c) in input.char_indices() {
+        match c {
+            '[' => in_label = true,
+            ']' => in_label = false,
+            '(' if !in_label => open.push(at),
+            ')' if !in_label => {
+                if let Some(start) = open.pop() {
+                    brackets.insert(start, at);
+                }
+            }
+            _ => {}
+        }
+    }
+    brackets
+}
+struct Builder<'a> {
+    tokens: &'a [Token],
+    spans: &'a [Range<usize>],
+    input: &'a str,
+    brackets: HashMap<usize, usize>,
+}
+/// An expression along with the bytes of the input it covers.
+type Built = (Expr, Range<usize>);
+/// A part of the tree waiting on the expression of a run of tokens after it, such as the body of a repeat.
+enum Pending {
+    Repeat(Expr, Range<usize>),
+    /// the left hand side of `&&` or `||`
+    Logic(BinaryOp, Expr, Range<usize>),
+    /// the condition of a conditional, waiting on the true branch which ends at the `Jump` at the index
+    Then(Expr, Range<usize>, usize),
+    /// the condition and true branch of a conditional, waiting on the false branch
+    Otherwise(Expr, Range<usize>, Expr),
+    /// the call to `floor()`, `ceil()` or `round()` and where it is written
+    Rounded(Function, Range<usize>),
+}
+impl Builder<'_> {
+    /// Builds the expression of the tokens in `range` along with the bytes of the input it covers.
+    ///
+    /// The parts of the tree built from a run of tokens of their own are kept on a stack of their own rather
+    /// than being built by recursion, so that deeply nested equations can't overflow the call stack.
+    fn build(&self, range: Range<usize>) -> Option<Built> {
+        let mut stack: Vec<Built> = Vec::new();
+        // what each run of tokens being built is for, with the stack and end of the run it is part of
+        let mut pending: Vec<(Pending, Vec<Built>, usize)> = Vec::new();
+        let mut end = range.end;
+        let mut index = range.start;
+        loop {
+            if index >= end {
+                let (built, extent) = match (stack.pop(), stack.is_empty()) {
+                    (Some(built), true) => built,
+                    _ => return None,
+                };
+                let Some((waiting, outer, outer_end)) = pending.pop() else {
+                    return Some((built, extent));
+                };
+                stack = outer;
+                end = outer_end;
+                let (expr, extent) = match waiting {
+                    Pending::Repeat(count, counted) => {
+                        let repeat = Expr::Repeat {
+                            count: Box::new(count),
+                            body: Box::new(ungroup(built)),
+                        };
+                        (repeat, join(&counted, &extent))
+                    }
+                    // the right hand side is followed by the `Truthy` that is skipped along with it
+                    Pending::Logic(op, lhs, left) => {
+                        index += 1;
+                        (binary(op, lhs, built), join(&left, &extent))
+                    }
+                    Pending::Then(condition, first, jump) => {
+                        let Token::Jump(length) = self.tokens.get(jump)? else {
+                            return None;
+                        };
+                        let otherwise = Pending::Otherwise(condition, first, built);
+                        pending.push((otherwise, std::mem::take(&mut stack), end));
+                        index = jump + 1;
+                        end = index + length;
+                        continue;
+                    }
+                    Pending::Otherwise(condition, first, then) => {
+                        let conditional = Expr::Conditional {
+                            condition: Box::new(condition),
+                            then: Box::new(then),
+                            otherwise: Box::new(built),
+                        };
+                        (conditional, join(&first, &extent))
+                    }
+                    Pending::Rounded(function, span) => {
+                        let args = vec![ungroup(built)];
+                        (Expr::Call { function, args }, span)
+                    }
+                };
+                stack.push(self.grouped(expr, extent));
+                continue;
+            }
+            let at = index;
+            index += 1;
+            let span = self.spans.get(at)?.clone();
+            // the tokens after these are built on their own before the expression is finished
+            let waiting = match self.tokens.get(at)? {
+                Token::Repeat(length) => {
+                    let (count, counted) = stack.pop()?;
+                    Some((Pending::Repeat(count, counted), *length))
+                }
+                Token::And(skip) | Token::Or(skip) => {
+                    let (lhs, left) = stack.pop()?;
+                    let op = match self.tokens[at] {
+                        Token::And(_) => BinaryOp::And,
+                        _ => BinaryOp::Or,
+                    };
+                    Some((Pending::Logic(op, lhs, left), skip.checked_sub(1)?))
+                }
+                // the true branch is followed by the `Jump` over the false branch
+                Token::JumpIfFalse(skip) => {
+                    let (condition, first) = stack.pop()?;
+                    let length = skip.checked_sub(1)?;
+                    Some((Pending::Then(condition, first, index + length), length))
+                }
+                // the argument of `floor()`, `ceil()` and `round()` comes after the token rounding it
+                Token::Rounded(rounding, length) => {
+                    let function = match rounding {
+                        RoundingPolicy::Floor => Function::Floor,
+                        RoundingPolicy::Ceil => Function::Ceil,
+                        _ => Function::Round,
+                    };
+                    Some((Pending::Rounded(function, span.clone()), *length))
+                }
+                _ => None,
+            };
+            if let Some((waiting, length)) = waiting {
+                pending.push((waiting, std::mem::take(&mut stack), end));
+                end = index + length;
+                continue;
+            }
+            let (expr, extent) = match self.tokens.get(at)? {
+                Token::Operand(value) => (Expr::Number(*value), span),
+                Token::Variable(name) => (Expr::Variable(name.clone()), span),
+                Token::Dice(die) => {
+                    let count = Expr::Number(die.number);
+                    (Expr::Dice(dice(die, count, sides(&die.kind))), span)
+                }
+                Token::DynamicDice {
+                    die,
+                    sides: dynamic,
+                } => {
+                    let sides = match dynamic {
+                        true => Sides::Expr(Box::new(ungroup(stack.pop()?.0))),
+                        false => sides(&die.kind),
+                    };
+                    let (count, counted) = stack.pop()?;
+                    (Expr::Dice(dice(die, count, sides)), join(&counted, &span))
+                }
+                Token::Plus
+                | Token::Minus
+                | Token::Times
+                | Token::Divide
+                | Token::Exponent
+                | Token::Compare(_) => {
+                    let (rhs, right) = stack.pop()?;
+                    let (lhs, left) = stack.pop()?;
+                    let op = match self.tokens[at] {
+                        Token::Plus => BinaryOp::Add,
+                        Token::Minus => BinaryOp::Subtract,
+                        Token::Times => BinaryOp::Multiply,
+                        Token::Divide => BinaryOp::Divide,
+                        Token::Exponent => BinaryOp::Power,
+                        Token::Compare(op) => BinaryOp::Compare(op),
+                        _ => return None,
+                    };
+                    (binary(op, lhs, rhs), join(&join(&left, &span), &right))
+                }
+                Token::Function(function, count) => {
+                    let start = stack.len().checked_sub(*count as usize)?;
+                    // the parentheses around a single argument belong to the call
+                    let args: Vec<Expr> = stack
+                        .split_off(start)
+                        .into_iter()
+                        .map(|arg| match *count {
+                            1 => ungroup(arg.0),
+                            _ => arg.0,
+                        })
+                        .collect();
+                    let call = Expr::Call {
+                        function: *function,
+                        args,
+                    };
+                    (call, span)
+                }
+                Token::Label(label) => {
+                    let (expr, extent) = stack.pop()?;
+                    let label = Expr::Label {
+                        expr: Box::new(expr),
+                        label: label.clone(),
+                    };
+                    (label, join(&extent, &span))
+                }
+                _ => return None,
+            };
+            stack.push(self.grouped(expr, extent));
+        }
+    }
+    /// Wraps `expr` in a group for every pair of parentheses written around it.
+    fn grouped(&self, mut expr: Expr, mut extent: Range<usize>) -> (Expr, Range<usize>) {
+        loop {
+            let before = self.input[..extent.start].trim_end();
+            let after = &self.input[extent.end..];
+            let close = extent.end + (after.len() - after.trim_start().len());
+            match before.strip_suffix('(') {
+                Some(before) if self.brackets.get(&before.len()) == Some(&close) => {
+                    expr = Expr::Group(Box::new(expr));
+                    extent = before.len()..close + 1;
+                }
+                _ => return (expr, extent),
+            }
+        }
+    }
+}
+/// Removes the parentheses that are part of the syntax around an expression, such as the body of a repeat.
+fn ungroup(expr: Expr) -> Expr {
+    match expr {
+        Expr::Group(expr) => *expr,
+        expr => expr,
+    }
+}
+fn binary(op: BinaryOp, lhs: Expr, rhs: Expr) -> Expr {
+    Expr::Binary {
+        op,
+        lhs: Box::new(lhs),
+        rhs: Box::new(rhs),
+    }
+}
+fn join(a: &Range<usize>, b: &Range<usize>) -> Range<usize> {
+    a.start.min(b.start)..a.end.max(b.end)
+}
+fn sides(kind: &DieKind) -> Sides {
+    match kind {
+        DieKind::Standard(sides) => Sides::Number(*sides),
+        DieKind::Fudge => Sides::Fudge,
+        DieKind::Custom(faces) => Sides::Custom(faces.clone()),
+    }
+}
+fn dice(die: &Die, count: Expr, sides: Sides) -> Dice {
+    Dice {
+        count: Box::new(count),
+        sides,
+        keep: die.keep,
+        explode: die.explode,
+        reroll: die.reroll,
+        success: die.success,
+        failure: die.failure,
+    }
+}
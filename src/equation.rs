@@ -1,5 +1,16 @@
+use crate::ast;
 use crate::errors;
 use crate::roll;
+use dice_forge_core::equation::{compile, parse, Token};
+pub use dice_forge_core::equation::{
+    Compare, CompareOp, Dialect, Explode, ExplodeKind, Function, Keep, Limits, Reroll,
+    RoundingPolicy, DEFAULT_EXPLOSION_LIMIT, DEFAULT_OUTCOME_LIMIT, DEFAULT_REROLL_LIMIT,
+};
+use std::collections::HashMap;
+use std::fmt;
+use std::hash::{Hash, Hasher};
+use std::ops::Range;
+use std::str::FromStr;
 
 /// struct containing the Equation compiled for faster evaluation
 ///
@@ -10,8 +21,19 @@ use crate::roll;
 /// let my_equation = Equation::new("3d5").unwrap();
 /// let my_roll = my_equation.roll().unwrap();
 /// ````
+///
+/// Two equations are equal when they compile to the same thing and have the same limits and rounding, no matter
+/// how they were written, so "1d6(2)" and "1d6 * 2" are equal.
+#[derive(Debug, Clone)]
 pub struct Equation {
     pub(crate) compiled_equation: Vec<Token>,
+    /// the bytes of `input` each token of `compiled_equation` came from
+    pub(crate) spans: Vec<Range<usize>>,
+    pub(crate) input: String,
+    pub(crate) limits: Limits,
+    pub(crate) rounding: RoundingPolicy,
+    /// how `input` was written, needed to read it again
+    pub(crate) dialect: Dialect,
 }
 impl Equation {
     /// Compiles and returns a new `Equation` object.
@@ -26,6 +48,9 @@ impl Equation {
     /// The resulting `Equation` object can then be used to roll the dice and perform basic math operations
     /// without the need for recompilation.
     ///
+    /// While compiling, arithmetic on plain numbers such as the "2^2" below is worked out once instead of on
+    /// every roll, and dividing by a constant 0 is an error straight away.
+    ///
     /// # Example
     ///
     /// Creating a new `Equation` object and rolling the dice:
@@ -38,8 +63,133 @@ impl Equation {
     /// println!("Result: {}", result);
     /// ```
     pub fn new(input: &str) -> Result<Equation, errors::InvalidExpressionError> {
-        let compiled_equation = infix_to_postfix(input)?;
-        Ok(Equation { compiled_equation })
+        Equation::new_with_limits(input, Limits::default())
+    }
+    /// Compiles a new `Equation` object that must stay within `limits`, for equations typed by users who
+    /// can't be trusted not to roll "999999999d999999999".
+    ///
+    /// The length of the equation, how deeply it nests and the dice written in it are checked straight away,
+    /// while dice and repeats that are only worked out when rolling are checked each time it is rolled.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use dice_forge::equation::Limits;
+    /// use dice_forge::{Equation, InvalidExpressionError, ParseError};
+    ///
+    /// let limits = Limits::untrusted();
+    /// assert!(Equation::new_with_limits("4d6kh3", limits).is_ok());
+    /// assert!(Equation::new_with_limits("999999999d999999999", limits).is_err());
+    /// assert!(matches!(
+    ///     Equation::new_with_limits("5000d6", limits),
+    ///     Err(InvalidExpressionError::Parse(ParseError::TooManyDice(_)))
+    /// ));
+    ///
+    /// let limits = Limits {
+    ///     max_dice: 100,
+    ///     ..Limits::untrusted()
+    /// };
+    /// assert!(Equation::new_with_limits("100d6", limits).is_ok());
+    /// assert!(Equation::new_with_limits("101d6", limits).is_err());
+    /// ```
+    pub fn new_with_limits(
+        input: &str,
+        limits: Limits,
+    ) -> Result<Equation, errors::InvalidExpressionError> {
+        Equation::new_with_dialect(input, Dialect::default(), limits)
+    }
+    /// Compiles a new `Equation` object written in `dialect`, such as the German "3W6", that must stay within
+    /// `limits`.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use dice_forge::equation::{Dialect, Limits};
+    /// use dice_forge::Equation;
+    ///
+    /// let equation = Equation::new_with_dialect("3W6 + 2", Dialect::german(), Limits::default()).unwrap();
+    /// assert_eq!(Equation::new("3d6 + 2").unwrap(), equation);
+    /// assert_eq!("3d6 + 2", equation.to_string());
+    ///
+    /// assert!(Equation::new("3D6 × 2 − 1").is_ok());
+    /// let strict = Dialect {
+    ///     case_sensitive: true,
+    ///     ..Dialect::default()
+    /// };
+    /// assert!(Equation::new_with_dialect("3D6", strict, Limits::default()).is_err());
+    /// ```
+    pub fn new_with_dialect(
+        input: &str,
+        dialect: Dialect,
+        limits: Limits,
+    ) -> Result<Equation, errors::InvalidExpressionError> {
+        let (compiled_equation, spans) = compile(input, &dialect, &limits)?;
+        Ok(Equation {
+            compiled_equation,
+            spans,
+            input: input.to_string(),
+            limits,
+            rounding: RoundingPolicy::Truncate,
+            dialect,
+        })
+    }
+    /// Sets how many times a single exploding die may explode, replacing [`DEFAULT_EXPLOSION_LIMIT`].
+    ///
+    /// The limit keeps dice that always explode such as "d1!" from rolling forever, and is also
+    /// used to work out the highest possible result of exploding dice.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use dice_forge::Equation;
+    ///
+    /// let my_equation = Equation::new("1d6!").unwrap().with_explosion_limit(2);
+    ///
+    /// assert_eq!(18, my_equation.high().unwrap());
+    /// ```
+    pub fn with_explosion_limit(mut self, limit: u32) -> Equation {
+        self.limits.max_explosions = limit;
+        self
+    }
+    /// Sets how many times a single die may be rerolled by an "r" modifier, replacing [`DEFAULT_REROLL_LIMIT`].
+    ///
+    /// If a die still matches its reroll condition after this many rerolls, rolling returns
+    /// `EvalError::RerollLimit`.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use dice_forge::Equation;
+    ///
+    /// let my_equation = Equation::new("1d20r<10").unwrap().with_reroll_limit(1000);
+    ///
+    /// assert!(my_equation.roll().unwrap() >= 10);
+    /// ```
+    pub fn with_reroll_limit(mut self, limit: u32) -> Equation {
+        self.limits.max_rerolls = limit;
+        self
+    }
+    /// Sets how "/" rounds its result, replacing the default of truncating towards zero.
+    ///
+    /// The policy is also used for the average of dice, and so applies to low(), high(), average() and
+    /// range() as well as roll(). floor(), ceil() and round() work out their whole argument keeping its fractions
+    /// and round it once their own way, and the f64 methods such as roll_f64() keep the fractions instead.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use dice_forge::Equation;
+    /// use dice_forge::equation::RoundingPolicy;
+    ///
+    /// let halved = Equation::new("(1-8)/2").unwrap().with_rounding(RoundingPolicy::Floor);
+    /// assert_eq!(-4, halved.roll().unwrap());
+    ///
+    /// let average = Equation::new("1d6").unwrap().with_rounding(RoundingPolicy::HalfUp);
+    /// assert_eq!(4, average.average().unwrap());
+    /// ```
+    pub fn with_rounding(mut self, rounding: RoundingPolicy) -> Equation {
+        self.rounding = rounding;
+        self
     }
     /// Rolls the given `Equation` object.
     ///
@@ -83,8 +233,7 @@ impl Equation {
     /// ```
     #[inline(always)]
     pub fn roll(&self) -> Result<i32, errors::InvalidExpressionError> {
-        //     todo!();
-        Ok(roll::process(self, RollType::Default)?)
+        roll::process(self, RollType::Default, &HashMap::new())
     }
     /// calculates the product of the equation assuming the average roll of all die in the equation
     ///
@@ -96,7 +245,7 @@ impl Equation {
     /// ````
     #[inline(always)]
     pub fn average(&self) -> Result<i32, errors::InvalidExpressionError> {
-        Ok(roll::process(self, RollType::Average)?)
+        roll::process(self, RollType::Average, &HashMap::new())
     }
     /// Calculates the range of possible values that can be produced by the equation.
     ///
@@ -116,8 +265,8 @@ impl Equation {
     /// ```
     #[inline(always)]
     pub fn range(&self) -> Result<(i32, i32), errors::InvalidExpressionError> {
-        let low = roll::process(self, RollType::Low)?;
-        let high = roll::process(self, RollType::High)?;
+        let low = roll::process(self, RollType::Low, &HashMap::new())?;
+        let high = roll::process(self, RollType::High, &HashMap::new())?;
         Ok((low, high))
     }
     /// Calculates the lowest possible value that can be produced by the equation.
@@ -138,7 +287,7 @@ impl Equation {
     /// ```
     #[inline(always)]
     pub fn low(&self) -> Result<i32, errors::InvalidExpressionError> {
-        Ok(roll::process(self, RollType::Low)?)
+        roll::process(self, RollType::Low, &HashMap::new())
     }
     /// Calculates the highest possible value that can be produced by the equation.
     ///
@@ -158,7 +307,7 @@ impl Equation {
     /// ```
     #[inline(always)]
     pub fn high(&self) -> Result<i32, errors::InvalidExpressionError> {
-        Ok(roll::process(self, RollType::High)?)
+        roll::process(self, RollType::High, &HashMap::new())
     }
     /// Rolls the given `Equation` object with advantage.
     ///
@@ -277,221 +426,753 @@ impl Equation {
     /// ```
     #[inline(always)]
     pub fn emphasis(&self) -> Result<i32, errors::InvalidExpressionError> {
-        Ok(roll::process(self, RollType::Emphasis)?)
+        roll::process(self, RollType::Emphasis, &HashMap::new())
     }
-}
-pub(crate) enum RollType {
-    Default,
-    Average,
-    Low,
-    High,
-    Emphasis,
-}
-
-#[derive(Clone, Copy)]
-pub(crate) enum Token {
-    Operand(u32),
-    Plus,
-    Minus,
-    Times,
-    Divide,
-    Exponent,
-    L,
-    Dice(Die),
-}
-#[derive(Clone, Copy)]
-pub(crate) struct Die {
-    pub(crate) number: u32,
-    pub(crate) sides: u32,
-}
-
-pub(crate) fn infix_to_postfix(input: &str) -> Result<Vec<Token>, errors::InvalidExpressionError> {
-    let mut output_queue: Vec<Token> = Vec::with_capacity(input.len());
-    let mut operator_stack: Vec<Token> = Vec::with_capacity(input.len());
-    let mut last_token_was_operand = false;
-    let mut last_token_was_die = false;
-    let mut error = None;
-
-    for token in input.chars().filter(|c| !c.is_whitespace()) {
-        match token {
-            '0'..='9' => {
-                if last_token_was_operand {
-                    let digit: u32;
-                    if let Token::Operand(value) = output_queue.pop().unwrap() {
-                        digit = value * 10 + token.to_digit(10).unwrap();
-                        output_queue.push(Token::Operand(digit));
-                        last_token_was_operand = true;
-                    } else {
-                        panic!()
-                    }
-                } else if last_token_was_die {
-                    if let Token::Dice(cdie) = output_queue.pop().unwrap() {
-                        let number = cdie.number;
-                        let sides = cdie.sides * 10 + token.to_digit(10).unwrap();
-                        output_queue.push(Token::Dice(Die { number, sides }));
-                        last_token_was_die = true;
-                    } else {
-                        panic!()
-                    }
-                } else {
-                    let digit = token.to_digit(10).unwrap();
-                    output_queue.push(Token::Operand(digit));
-                    last_token_was_operand = true;
-                }
-            }
-            '(' => {
-                if last_token_was_operand | last_token_was_die {
-                    operator_stack.push(Token::Times);
-                }
-                operator_stack.push(Token::L);
-                last_token_was_operand = false;
-                last_token_was_die = false;
-            }
-            ')' => {
-                while let Some(operator) = operator_stack.pop() {
-                    if let Token::L = operator {
-                        break;
-                    } else {
-                        output_queue.push(operator);
-                    }
-                }
-                last_token_was_operand = false;
-                last_token_was_die = false;
-            }
-            '+' => {
-                if !last_token_was_operand && !last_token_was_die {
-                    output_queue.push(Token::Operand(0));
-                }
-                let token_precedence = operator_precedence(Token::Plus);
-                while let Some(&top) = operator_stack.last() {
-                    if let Token::L = top {
-                        break;
-                    } else {
-                        let top_precedence = operator_precedence(top);
-                        if token_precedence <= top_precedence {
-                            output_queue.push(operator_stack.pop().unwrap());
-                        } else {
-                            break;
-                        }
-                    }
-                }
-                operator_stack.push(Token::Plus);
-                last_token_was_operand = false;
-                last_token_was_die = false;
-            }
-            '-' => {
-                if !last_token_was_operand && !last_token_was_die {
-                    output_queue.push(Token::Operand(0));
-                }
-                let token_precedence = operator_precedence(Token::Minus);
-                while let Some(&top) = operator_stack.last() {
-                    if let Token::L = top {
-                        break;
-                    } else {
-                        let top_precedence = operator_precedence(top);
-                        if token_precedence <= top_precedence {
-                            output_queue.push(operator_stack.pop().unwrap());
-                        } else {
-                            break;
-                        }
-                    }
-                }
-                operator_stack.push(Token::Minus);
-                last_token_was_operand = false;
-                last_token_was_die = false;
-            }
-            '*' => {
-                let token_precedence = operator_precedence(Token::Times);
-                while let Some(&top) = operator_stack.last() {
-                    if let Token::L = top {
-                        break;
-                    } else {
-                        let top_precedence = operator_precedence(top);
-                        if token_precedence <= top_precedence {
-                            output_queue.push(operator_stack.pop().unwrap());
-                        } else {
-                            break;
-                        }
-                    }
-                }
-                operator_stack.push(Token::Times);
-                last_token_was_operand = false;
-                last_token_was_die = false;
-            }
-            '/' => {
-                let token_precedence = operator_precedence(Token::Divide);
-                while let Some(&top) = operator_stack.last() {
-                    if let Token::L = top {
-                        break;
-                    } else {
-                        let top_precedence = operator_precedence(top);
-                        if token_precedence <= top_precedence {
-                            output_queue.push(operator_stack.pop().unwrap());
-                        } else {
-                            break;
-                        }
-                    }
-                }
-                operator_stack.push(Token::Divide);
-                last_token_was_operand = false;
-                last_token_was_die = false;
-            }
-            '^' => {
-                let token_precedence = operator_precedence(Token::Exponent);
-                while let Some(&top) = operator_stack.last() {
-                    let top_precedence = operator_precedence(top);
-                    if token_precedence <= top_precedence {
-                        output_queue.push(operator_stack.pop().unwrap());
-                    } else {
-                        break;
-                    }
-                }
-                operator_stack.push(Token::Exponent);
-                last_token_was_operand = false;
-                last_token_was_die = false;
-            }
-            'd' => {
-                if last_token_was_operand {
-                    if let Token::Operand(die_count) = output_queue.pop().unwrap() {
-                        output_queue.push(Token::Dice(Die {
-                            number: die_count,
-                            sides: 0,
-                        }))
-                    }
-                } else {
-                    output_queue.push(Token::Dice(Die {
-                        number: 1,
-                        sides: 0,
-                    }))
+    /// Rolls the given `Equation` object, also totalling the terms given each label.
+    ///
+    /// Any term can be followed by a label in square brackets such as `1d8[slashing] + 2d6[fire] + 3`. Each
+    /// label's subtotal is the sum of the terms it was attached to, in the order the labels first appear,
+    /// while terms without a label only count towards the total.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use dice_forge::Equation;
+    ///
+    /// let attack = Equation::new("1d8[slashing] + 2d6[fire] + 3").unwrap();
+    /// let damage = attack.roll_labeled().unwrap();
+    ///
+    /// println!("Total: {}", damage.total);
+    /// for (label, subtotal) in &damage.labels {
+    ///     println!("{}: {}", label, subtotal);
+    /// }
+    /// ```
+    #[inline(always)]
+    pub fn roll_labeled(&self) -> Result<LabeledRoll, errors::InvalidExpressionError> {
+        roll::process_labeled(self, RollType::Default, &HashMap::new())
+    }
+    /// Rolls the given `Equation` object using `vars` for the value of each variable, also totalling
+    /// the terms given each label.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use dice_forge::Equation;
+    /// use std::collections::HashMap;
+    ///
+    /// let attack = Equation::new("(1d8 + @str_mod)[slashing] + 2d6[fire]").unwrap();
+    /// let vars = HashMap::from([("str_mod", 3)]);
+    ///
+    /// let damage = attack.roll_labeled_with_vars(&vars).unwrap();
+    /// assert_eq!("slashing", damage.labels[0].0);
+    /// ```
+    #[inline(always)]
+    pub fn roll_labeled_with_vars(
+        &self,
+        vars: &HashMap<&str, i32>,
+    ) -> Result<LabeledRoll, errors::InvalidExpressionError> {
+        roll::process_labeled(self, RollType::Default, vars)
+    }
+    /// Rolls an equation made of a repeat group such as "6x(4d6kh3)", returning every result.
+    ///
+    /// "Nx(...)" or "N#(...)" rolls the expression in parentheses N times giving a list of results. A
+    /// list can't be used with other operators, so "6x(1d6)+2" returns
+    /// `EvalError::ListAsNumber`, but it can be totalled with "sum(6x(1d6))+2" or passed
+    /// to min() and max(). Equations without a repeat group return a single result.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use dice_forge::Equation;
+    ///
+    /// let stats = Equation::new("6x(4d6kh3)").unwrap().roll_many().unwrap();
+    ///
+    /// assert_eq!(6, stats.len());
+    /// println!("Stats: {:?}", stats);
+    /// ```
+    #[inline(always)]
+    pub fn roll_many(&self) -> Result<Vec<i32>, errors::InvalidExpressionError> {
+        roll::process_many(self, RollType::Default, &HashMap::new())
+    }
+    /// Rolls an equation made of a repeat group using `vars` for the value of each variable.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use dice_forge::Equation;
+    /// use std::collections::HashMap;
+    ///
+    /// let attacks = Equation::new("@attacks#(1d20+5)").unwrap();
+    /// let vars = HashMap::from([("attacks", 3)]);
+    ///
+    /// assert_eq!(3, attacks.roll_many_with_vars(&vars).unwrap().len());
+    /// ```
+    #[inline(always)]
+    pub fn roll_many_with_vars(
+        &self,
+        vars: &HashMap<&str, i32>,
+    ) -> Result<Vec<i32>, errors::InvalidExpressionError> {
+        roll::process_many(self, RollType::Default, vars)
+    }
+    /// Returns the name of every variable used in the equation, in the order they first appear.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use dice_forge::Equation;
+    ///
+    /// let attack = Equation::new("1d20 + @str_mod + @prof").unwrap();
+    ///
+    /// assert_eq!(vec!["str_mod", "prof"], attack.variables());
+    /// ```
+    pub fn variables(&self) -> Vec<&str> {
+        let mut variables: Vec<&str> = Vec::new();
+        for token in &self.compiled_equation {
+            if let Token::Variable(name) = token {
+                if !variables.contains(&name.as_str()) {
+                    variables.push(name);
                 }
-                last_token_was_operand = false;
-                last_token_was_die = true;
-            }
-            _ => {
-                error = Some(errors::InvalidExpressionError::InvalidToken(token));
-                break;
             }
         }
+        variables
     }
-
-    if let Some(err) = error {
-        return Err(err);
+    /// Returns the tree of the equation, for inspecting or changing it without parsing it again.
+    ///
+    /// Parentheses written in the equation are kept as [`Expr::Group`](crate::ast::Expr::Group), while
+    /// the parentheses around function arguments, the body of a repeat or the sides of a die are part
+    /// of those expressions.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use dice_forge::ast::{BinaryOp, Expr};
+    /// use dice_forge::Equation;
+    ///
+    /// match Equation::new("1d20 + 5").unwrap().ast() {
+    ///     Expr::Binary { op, rhs, .. } => {
+    ///         assert_eq!(BinaryOp::Add, op);
+    ///         assert_eq!(Expr::Number(5), *rhs);
+    ///     }
+    ///     _ => unreachable!(),
+    /// }
+    /// ```
+    pub fn ast(&self) -> ast::Expr {
+        // the compiled tokens have been simplified, so the tree is built from the equation as it was written
+        let (tokens, spans) =
+            parse(&self.input, &self.dialect, &self.limits).expect("the equation compiled before");
+        ast::from_postfix(&tokens, &spans, &self.input)
+            .expect("a compiled equation always forms a tree")
     }
-
-    while let Some(operator) = operator_stack.pop() {
-        output_queue.push(operator);
+    /// Compiles an `Equation` object from a tree, such as one built by hand or changed by a [`Fold`](crate::ast::Fold).
+    ///
+    /// The tree is compiled from the way it is shown, so any errors point at that text. That text is always the
+    /// standard notation, so the dialect of the equation a tree came from doesn't matter, but its limits and
+    /// rounding aren't part of the tree. This uses `Limits::default()`, see [`Equation::from_ast_with_limits`], and
+    /// the rounding can be set again with [`Equation::with_rounding`].
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use dice_forge::ast::{BinaryOp, Dice, Expr, Sides};
+    /// use dice_forge::Equation;
+    ///
+    /// let dice = Expr::Dice(Dice {
+    ///     count: Box::new(Expr::Number(2)),
+    ///     sides: Sides::Number(6),
+    ///     keep: None,
+    ///     explode: None,
+    ///     reroll: None,
+    ///     success: None,
+    ///     failure: None,
+    /// });
+    /// let ast = Expr::Binary {
+    ///     op: BinaryOp::Add,
+    ///     lhs: Box::new(dice),
+    ///     rhs: Box::new(Expr::Number(3)),
+    /// };
+    ///
+    /// let equation = Equation::from_ast(&ast).unwrap();
+    /// assert_eq!((5, 15), equation.range().unwrap());
+    /// ```
+    pub fn from_ast(ast: &ast::Expr) -> Result<Equation, errors::InvalidExpressionError> {
+        Equation::from_ast_with_limits(ast, Limits::default())
+    }
+    /// Compiles an `Equation` object from a tree that must stay within `limits`, as [`Equation::new_with_limits`]
+    /// does for text.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use dice_forge::equation::Limits;
+    /// use dice_forge::Equation;
+    ///
+    /// let limits = Limits::untrusted();
+    /// let ast = Equation::new("5000d6").unwrap().ast();
+    /// assert!(Equation::from_ast(&ast).is_ok());
+    /// assert!(Equation::from_ast_with_limits(&ast, limits).is_err());
+    ///
+    /// let typed = Equation::new_with_limits("4d6kh3", limits).unwrap();
+    /// let rebuilt = Equation::from_ast_with_limits(&typed.ast(), limits).unwrap();
+    /// assert_eq!(typed, rebuilt);
+    /// assert_ne!(typed, Equation::from_ast(&typed.ast()).unwrap());
+    /// ```
+    pub fn from_ast_with_limits(
+        ast: &ast::Expr,
+        limits: Limits,
+    ) -> Result<Equation, errors::InvalidExpressionError> {
+        Equation::new_with_limits(&ast.to_string(), limits)
+    }
+    /// Builds an equation from the tokens the `dice!` macro compiled `input` into, as if by [`Equation::new`].
+    #[doc(hidden)]
+    pub fn __from_compiled(
+        compiled_equation: Vec<Token>,
+        spans: Vec<Range<usize>>,
+        input: &str,
+    ) -> Equation {
+        Equation {
+            compiled_equation,
+            spans,
+            input: input.to_string(),
+            limits: Limits::default(),
+            rounding: RoundingPolicy::Truncate,
+            dialect: Dialect::default(),
+        }
+    }
+    /// Rolls the given `Equation` object using `vars` for the value of each variable.
+    ///
+    /// Variables are written as "@" followed by a name made of letters, numbers and underscores, such as
+    /// "@str_mod". Rolling an equation that uses a variable missing from `vars` returns
+    /// `EvalError::UnboundVariable`.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use dice_forge::Equation;
+    /// use std::collections::HashMap;
+    ///
+    /// let attack = Equation::new("1d20 + @str_mod + @prof").unwrap();
+    /// let vars = HashMap::from([("str_mod", 3), ("prof", 2)]);
+    ///
+    /// println!("Result: {}", attack.roll_with_vars(&vars).unwrap());
+    /// ```
+    #[inline(always)]
+    pub fn roll_with_vars(
+        &self,
+        vars: &HashMap<&str, i32>,
+    ) -> Result<i32, errors::InvalidExpressionError> {
+        roll::process(self, RollType::Default, vars)
+    }
+    /// Calculates the average result of the equation using `vars` for the value of each variable.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use dice_forge::Equation;
+    /// use std::collections::HashMap;
+    ///
+    /// let attack = Equation::new("1d20 + @str_mod").unwrap();
+    /// let vars = HashMap::from([("str_mod", 3)]);
+    ///
+    /// assert_eq!(13, attack.average_with_vars(&vars).unwrap());
+    /// ```
+    #[inline(always)]
+    pub fn average_with_vars(
+        &self,
+        vars: &HashMap<&str, i32>,
+    ) -> Result<i32, errors::InvalidExpressionError> {
+        roll::process(self, RollType::Average, vars)
+    }
+    /// Calculates the range of the equation using `vars` for the value of each variable.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use dice_forge::Equation;
+    /// use std::collections::HashMap;
+    ///
+    /// let attack = Equation::new("1d20 + @str_mod").unwrap();
+    /// let vars = HashMap::from([("str_mod", 3)]);
+    ///
+    /// assert_eq!((4, 23), attack.range_with_vars(&vars).unwrap());
+    /// ```
+    #[inline(always)]
+    pub fn range_with_vars(
+        &self,
+        vars: &HashMap<&str, i32>,
+    ) -> Result<(i32, i32), errors::InvalidExpressionError> {
+        let low = roll::process(self, RollType::Low, vars)?;
+        let high = roll::process(self, RollType::High, vars)?;
+        Ok((low, high))
+    }
+    /// Calculates the lowest possible value of the equation using `vars` for the value of each variable.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use dice_forge::Equation;
+    /// use std::collections::HashMap;
+    ///
+    /// let attack = Equation::new("1d20 + @str_mod").unwrap();
+    /// let vars = HashMap::from([("str_mod", 3)]);
+    ///
+    /// assert_eq!(4, attack.low_with_vars(&vars).unwrap());
+    /// ```
+    #[inline(always)]
+    pub fn low_with_vars(
+        &self,
+        vars: &HashMap<&str, i32>,
+    ) -> Result<i32, errors::InvalidExpressionError> {
+        roll::process(self, RollType::Low, vars)
+    }
+    /// Calculates the highest possible value of the equation using `vars` for the value of each variable.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use dice_forge::Equation;
+    /// use std::collections::HashMap;
+    ///
+    /// let attack = Equation::new("1d20 + @str_mod").unwrap();
+    /// let vars = HashMap::from([("str_mod", 3)]);
+    ///
+    /// assert_eq!(23, attack.high_with_vars(&vars).unwrap());
+    /// ```
+    #[inline(always)]
+    pub fn high_with_vars(
+        &self,
+        vars: &HashMap<&str, i32>,
+    ) -> Result<i32, errors::InvalidExpressionError> {
+        roll::process(self, RollType::High, vars)
+    }
+    /// Rolls the given `Equation` object keeping any fractions instead of truncating every division.
+    ///
+    /// Functions such as floor() and round() still round their argument, and the number of dice or repeats
+    /// taken from an expression uses its whole part.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use dice_forge::Equation;
+    ///
+    /// let half_damage = Equation::new("7/2").unwrap().roll_f64().unwrap();
+    /// assert_eq!(3.5, half_damage);
+    /// ```
+    #[inline(always)]
+    pub fn roll_f64(&self) -> Result<f64, errors::InvalidExpressionError> {
+        roll::process(self, RollType::Default, &HashMap::new())
+    }
+    /// Calculates the exact average result of the equation without truncating fractions.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use dice_forge::Equation;
+    ///
+    /// assert_eq!(3.5, Equation::new("1d6").unwrap().average_f64().unwrap());
+    /// assert_eq!(1.75, Equation::new("1d6/2").unwrap().average_f64().unwrap());
+    /// ```
+    #[inline(always)]
+    pub fn average_f64(&self) -> Result<f64, errors::InvalidExpressionError> {
+        roll::process(self, RollType::Average, &HashMap::new())
+    }
+    /// Calculates the range of the equation without truncating fractions.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use dice_forge::Equation;
+    ///
+    /// assert_eq!((0.5, 3.0), Equation::new("1d6/2").unwrap().range_f64().unwrap());
+    /// ```
+    #[inline(always)]
+    pub fn range_f64(&self) -> Result<(f64, f64), errors::InvalidExpressionError> {
+        let low = roll::process(self, RollType::Low, &HashMap::new())?;
+        let high = roll::process(self, RollType::High, &HashMap::new())?;
+        Ok((low, high))
+    }
+    /// Calculates the lowest possible value of the equation without truncating fractions.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use dice_forge::Equation;
+    ///
+    /// assert_eq!(0.5, Equation::new("1d6/2").unwrap().low_f64().unwrap());
+    /// ```
+    #[inline(always)]
+    pub fn low_f64(&self) -> Result<f64, errors::InvalidExpressionError> {
+        roll::process(self, RollType::Low, &HashMap::new())
+    }
+    /// Calculates the highest possible value of the equation without truncating fractions.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use dice_forge::Equation;
+    ///
+    /// assert_eq!(2.5, Equation::new("1d5/2").unwrap().high_f64().unwrap());
+    /// ```
+    #[inline(always)]
+    pub fn high_f64(&self) -> Result<f64, errors::InvalidExpressionError> {
+        roll::process(self, RollType::High, &HashMap::new())
+    }
+    /// Rolls the given `Equation` object keeping any fractions, using `vars` for the value of each variable.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use dice_forge::Equation;
+    /// use std::collections::HashMap;
+    ///
+    /// let heal = Equation::new("@level / 2").unwrap();
+    /// let vars = HashMap::from([("level", 5)]);
+    ///
+    /// assert_eq!(2.5, heal.roll_f64_with_vars(&vars).unwrap());
+    /// ```
+    #[inline(always)]
+    pub fn roll_f64_with_vars(
+        &self,
+        vars: &HashMap<&str, i32>,
+    ) -> Result<f64, errors::InvalidExpressionError> {
+        roll::process(self, RollType::Default, vars)
     }
+    /// Calculates the exact average result of the equation using `vars` for the value of each variable.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use dice_forge::Equation;
+    /// use std::collections::HashMap;
+    ///
+    /// let attack = Equation::new("1d8 + @str_mod").unwrap();
+    /// let vars = HashMap::from([("str_mod", 3)]);
+    ///
+    /// assert_eq!(7.5, attack.average_f64_with_vars(&vars).unwrap());
+    /// ```
+    #[inline(always)]
+    pub fn average_f64_with_vars(
+        &self,
+        vars: &HashMap<&str, i32>,
+    ) -> Result<f64, errors::InvalidExpressionError> {
+        roll::process(self, RollType::Average, vars)
+    }
+    /// Calculates the range of the equation without truncating fractions, using `vars` for the value of each
+    /// variable.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use dice_forge::Equation;
+    /// use std::collections::HashMap;
+    ///
+    /// let heal = Equation::new("1d4 + @level / 2").unwrap();
+    /// let vars = HashMap::from([("level", 5)]);
+    ///
+    /// assert_eq!((3.5, 6.5), heal.range_f64_with_vars(&vars).unwrap());
+    /// ```
+    #[inline(always)]
+    pub fn range_f64_with_vars(
+        &self,
+        vars: &HashMap<&str, i32>,
+    ) -> Result<(f64, f64), errors::InvalidExpressionError> {
+        let low = roll::process(self, RollType::Low, vars)?;
+        let high = roll::process(self, RollType::High, vars)?;
+        Ok((low, high))
+    }
+    /// Calculates the lowest possible value of the equation without truncating fractions, using `vars` for the
+    /// value of each variable.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use dice_forge::Equation;
+    /// use std::collections::HashMap;
+    ///
+    /// let heal = Equation::new("1d4 + @level / 2").unwrap();
+    /// let vars = HashMap::from([("level", 5)]);
+    ///
+    /// assert_eq!(3.5, heal.low_f64_with_vars(&vars).unwrap());
+    /// ```
+    #[inline(always)]
+    pub fn low_f64_with_vars(
+        &self,
+        vars: &HashMap<&str, i32>,
+    ) -> Result<f64, errors::InvalidExpressionError> {
+        roll::process(self, RollType::Low, vars)
+    }
+    /// Calculates the highest possible value of the equation without truncating fractions, using `vars` for the
+    /// value of each variable.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use dice_forge::Equation;
+    /// use std::collections::HashMap;
+    ///
+    /// let heal = Equation::new("1d4 + @level / 2").unwrap();
+    /// let vars = HashMap::from([("level", 5)]);
+    ///
+    /// assert_eq!(6.5, heal.high_f64_with_vars(&vars).unwrap());
+    /// ```
+    #[inline(always)]
+    pub fn high_f64_with_vars(
+        &self,
+        vars: &HashMap<&str, i32>,
+    ) -> Result<f64, errors::InvalidExpressionError> {
+        roll::process(self, RollType::High, vars)
+    }
+    /// Rolls the given `Equation` object with 64 bit whole numbers, for equations whose results are too large
+    /// for `roll()`.
+    ///
+    /// Numbers written in the equation and the values of variables are still limited to `i32`, but every
+    /// result worked out from them can be as large as an `i64`.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use dice_forge::Equation;
+    ///
+    /// let equation = Equation::new("1000d1000 * 1000d1000").unwrap();
+    /// assert!(equation.roll_i64().unwrap() >= 1_000_000);
+    /// assert!(Equation::new("100000 * 100000").unwrap().roll().is_err());
+    /// assert_eq!(10_000_000_000, Equation::new("100000 * 100000").unwrap().roll_i64().unwrap());
+    /// ```
+    #[inline(always)]
+    pub fn roll_i64(&self) -> Result<i64, errors::InvalidExpressionError> {
+        roll::process(self, RollType::Default, &HashMap::new())
+    }
+    /// Calculates the average result of the equation with 64 bit whole numbers.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use dice_forge::Equation;
+    ///
+    /// assert_eq!(2_502_500_000, Equation::new("1000d1000 * 5000").unwrap().average_i64().unwrap());
+    /// ```
+    #[inline(always)]
+    pub fn average_i64(&self) -> Result<i64, errors::InvalidExpressionError> {
+        roll::process(self, RollType::Average, &HashMap::new())
+    }
+    /// Calculates the range of the equation with 64 bit whole numbers.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use dice_forge::Equation;
+    ///
+    /// let range = Equation::new("1000d1000 * 1000d1000").unwrap().range_i64().unwrap();
+    /// assert_eq!((1_000_000, 1_000_000_000_000), range);
+    /// ```
+    #[inline(always)]
+    pub fn range_i64(&self) -> Result<(i64, i64), errors::InvalidExpressionError> {
+        let low = roll::process(self, RollType::Low, &HashMap::new())?;
+        let high = roll::process(self, RollType::High, &HashMap::new())?;
+        Ok((low, high))
+    }
+    /// Calculates the lowest possible value of the equation with 64 bit whole numbers.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use dice_forge::Equation;
+    ///
+    /// assert_eq!(1_000_000, Equation::new("1000d1000 * 1000d1000").unwrap().low_i64().unwrap());
+    /// ```
+    #[inline(always)]
+    pub fn low_i64(&self) -> Result<i64, errors::InvalidExpressionError> {
+        roll::process(self, RollType::Low, &HashMap::new())
+    }
+    /// Calculates the highest possible value of the equation with 64 bit whole numbers.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use dice_forge::Equation;
+    ///
+    /// assert_eq!(1_000_000_000_000, Equation::new("1000d1000 * 1000d1000").unwrap().high_i64().unwrap());
+    /// ```
+    #[inline(always)]
+    pub fn high_i64(&self) -> Result<i64, errors::InvalidExpressionError> {
+        roll::process(self, RollType::High, &HashMap::new())
+    }
+    /// Rolls the given `Equation` object with 64 bit whole numbers, using `vars` for the value of each variable.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use dice_forge::Equation;
+    /// use std::collections::HashMap;
+    ///
+    /// let hoard = Equation::new("@gold * 1000000").unwrap();
+    /// let vars = HashMap::from([("gold", 5000)]);
+    ///
+    /// assert_eq!(5_000_000_000, hoard.roll_i64_with_vars(&vars).unwrap());
+    /// ```
+    #[inline(always)]
+    pub fn roll_i64_with_vars(
+        &self,
+        vars: &HashMap<&str, i32>,
+    ) -> Result<i64, errors::InvalidExpressionError> {
+        roll::process(self, RollType::Default, vars)
+    }
+    /// Calculates the average result of the equation with 64 bit whole numbers, using `vars` for the value of
+    /// each variable.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use dice_forge::Equation;
+    /// use std::collections::HashMap;
+    ///
+    /// let hoard = Equation::new("@chests * 1000d1000000").unwrap();
+    /// let vars = HashMap::from([("chests", 10)]);
+    ///
+    /// assert_eq!(5_000_005_000, hoard.average_i64_with_vars(&vars).unwrap());
+    /// ```
+    #[inline(always)]
+    pub fn average_i64_with_vars(
+        &self,
+        vars: &HashMap<&str, i32>,
+    ) -> Result<i64, errors::InvalidExpressionError> {
+        roll::process(self, RollType::Average, vars)
+    }
+    /// Calculates the range of the equation with 64 bit whole numbers, using `vars` for the value of each
+    /// variable.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use dice_forge::Equation;
+    /// use std::collections::HashMap;
+    ///
+    /// let hoard = Equation::new("@gold * 1d1000000").unwrap();
+    /// let vars = HashMap::from([("gold", 5000)]);
+    ///
+    /// assert_eq!((5000, 5_000_000_000), hoard.range_i64_with_vars(&vars).unwrap());
+    /// ```
+    #[inline(always)]
+    pub fn range_i64_with_vars(
+        &self,
+        vars: &HashMap<&str, i32>,
+    ) -> Result<(i64, i64), errors::InvalidExpressionError> {
+        let low = roll::process(self, RollType::Low, vars)?;
+        let high = roll::process(self, RollType::High, vars)?;
+        Ok((low, high))
+    }
+    /// Calculates the lowest possible value of the equation with 64 bit whole numbers, using `vars` for the
+    /// value of each variable.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use dice_forge::Equation;
+    /// use std::collections::HashMap;
+    ///
+    /// let hoard = Equation::new("@gold * (1d1000000 + 1000000)").unwrap();
+    /// let vars = HashMap::from([("gold", 5000)]);
+    ///
+    /// assert_eq!(5_000_005_000, hoard.low_i64_with_vars(&vars).unwrap());
+    /// ```
+    #[inline(always)]
+    pub fn low_i64_with_vars(
+        &self,
+        vars: &HashMap<&str, i32>,
+    ) -> Result<i64, errors::InvalidExpressionError> {
+        roll::process(self, RollType::Low, vars)
+    }
+    /// Calculates the highest possible value of the equation with 64 bit whole numbers, using `vars` for the
+    /// value of each variable.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use dice_forge::Equation;
+    /// use std::collections::HashMap;
+    ///
+    /// let hoard = Equation::new("@gold * 1d1000000").unwrap();
+    /// let vars = HashMap::from([("gold", 5000)]);
+    ///
+    /// assert_eq!(5_000_000_000, hoard.high_i64_with_vars(&vars).unwrap());
+    /// ```
+    #[inline(always)]
+    pub fn high_i64_with_vars(
+        &self,
+        vars: &HashMap<&str, i32>,
+    ) -> Result<i64, errors::InvalidExpressionError> {
+        roll::process(self, RollType::High, vars)
+    }
+}
+// the input and where each token came from only matter for showing errors
+impl PartialEq for Equation {
+    fn eq(&self, other: &Equation) -> bool {
+        self.compiled_equation == other.compiled_equation
+            && self.limits == other.limits
+            && self.rounding == other.rounding
+    }
+}
+impl Eq for Equation {}
+impl Hash for Equation {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        self.compiled_equation.hash(state);
+        self.limits.hash(state);
+        self.rounding.hash(state);
+    }
+}
+/// Shows the equation in a canonical form rebuilt from what it compiled to, with only the parentheses it needs.
+///
+/// The text can always be compiled back into an equal `Equation`, as long as the limits and rounding are set
+/// the same way again.
+///
+/// # Example
+///
+/// ```
+/// use dice_forge::Equation;
+///
+/// let equation = Equation::new("3 + 1d6(4+3^2)").unwrap();
+/// assert_eq!("3 + 1d6*(4+3^2)", equation.to_string());
+/// assert_eq!("1d20 + 5", Equation::new("((1d20)) + (5)").unwrap().to_string());
+/// ```
+impl fmt::Display for Equation {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        crate::ast::write_without_groups(f, &self.ast())
+    }
+}
+impl FromStr for Equation {
+    type Err = errors::InvalidExpressionError;
 
-    Ok(output_queue)
+    /// Compiles the equation the same as [`Equation::new`].
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use dice_forge::Equation;
+    ///
+    /// let equation: Equation = "2d6+3".parse().unwrap();
+    /// assert_eq!((5, 15), equation.range().unwrap());
+    /// ```
+    fn from_str(input: &str) -> Result<Equation, errors::InvalidExpressionError> {
+        Equation::new(input)
+    }
 }
-#[inline(always)]
-fn operator_precedence(token: Token) -> i32 {
-    match token {
-        Token::Plus | Token::Minus => 1,
-        Token::Times | Token::Divide => 2,
-        Token::Exponent => 3,
-        Token::Operand(_) => panic!("Expected operator, found operand"),
-        Token::L => 4,
-        Token::Dice(_) => panic!("Expected operator, found operand"),
+impl TryFrom<&str> for Equation {
+    type Error = errors::InvalidExpressionError;
+
+    fn try_from(input: &str) -> Result<Equation, errors::InvalidExpressionError> {
+        Equation::new(input)
     }
 }
+/// The result of [`Equation::roll_labeled`], the total along with the subtotal of every label.
+#[derive(Debug, Clone, PartialEq)]
+pub struct LabeledRoll {
+    pub total: i32,
+    /// every label and the sum of the terms it was attached to, in the order they first appear
+    pub labels: Vec<(String, i32)>,
+}
+pub(crate) enum RollType {
+    Default,
+    Average,
+    Low,
+    High,
+    Emphasis,
+}
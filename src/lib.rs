@@ -5,6 +5,37 @@
 //! To use the library, you create an Equation object by passing a string representing a valid mathematical expression
 //! that can include dice notation. Dice notation should be in the format "NdM" where N is the number of dice to roll,
 //! and M is the number of sides on each die. For example, "2d6" would roll two six-sided dice.
+//! "d%" is short for "d100" and "4dF" rolls four Fate dice, each landing on -1, 0 or +1. Dice with any faces can
+//! be written by listing them, "2d{1,1,2,3,5,8}" rolls two dice that land on one of those six faces.
+//! The number of dice and the sides can also be worked out when rolling by using parentheses or a variable,
+//! such as "(1d4)d6", "2d(1d3*4)" or "(@level)d6".
+//! Dice can be followed by keep or drop modifiers: "4d6kh3" keeps the highest 3 dice, "2d20kl1" keeps the lowest,
+//! and "4d6dl1" / "4d6dh1" drop the lowest or highest dice instead. Exploding dice roll again when they land on
+//! their highest face: "d6!" adds a new die, "d6!!" compounds onto the die that exploded and "d6!p" penetrates,
+//! subtracting 1 from every extra die. A compare point such as "d10!>8" changes which faces explode.
+//! Rerolls use the same compare points, "2d6r<=2" rerolls until the die shows 3 or more while "2d6ro<3" only
+//! rerolls once.
+//!
+//! A compare point directly after a die turns it into a success counting pool, so "10d10>=8f1" counts the dice
+//! showing 8 or more and subtracts the dice showing a 1 instead of adding the faces together.
+//!
+//! The functions min(), max(), abs(), clamp(), floor(), ceil() and round() can be used anywhere in an equation, for
+//! example "max(1, 1d6-2)" never rolls less than 1 and "floor(2d6/2)" halves a roll rounding down.
+//!
+//! Comparisons (==, !=, <, <=, >, >=) give 1 when true and 0 when false and can be combined with && and ||. Along
+//! with the ternary operator this allows a whole attack in one equation, "1d20+5 >= 15 ? 2d6+3 : 0" only rolls
+//! the damage when the attack hits. A compare point written directly after a die, as in "2d6>7", is read as a
+//! success counting pool, while "2d6 > 7" with a space, "1d20 == 20" and "1d6 != 3" compare the total.
+//!
+//! A repeat group rolls part of an equation several times and returns every result, "6x(4d6kh3)" rolls six stats
+//! and "3#(1d20+5)" rolls three attacks. Use roll_many() to get the results, or sum(), min() and max() to turn
+//! them back into a single number.
+//!
+//! Terms can be labelled with square brackets, such as `1d8[slashing] + 2d6[fire] + 3`. Labels don't change the
+//! result, but roll_labeled() returns the subtotal of each label alongside the total.
+//!
+//! Equations can also use variables such as "1d20+@str_mod" whose values are given each time the equation is
+//! rolled with roll_with_vars(), so a compiled equation can be reused as a character's stats change.
 //!
 //! Once you have an Equation object, you can call its roll() method to evaluate the expression and obtain a result.
 //! You can also call its high(), low(), average(), or range() methods to obtain information about the
@@ -12,6 +43,41 @@
 //! their are also methods to roll with advantage() or disadvantage() check the rest of the documentation
 //! for a full list and examples.
 //!
+//! Equations are worked out with whole numbers, so "7/2" gives 3 and the average of "1d6" is 3. roll_f64(),
+//! average_f64() and the other _f64 methods keep the fractions until the end instead, giving 3.5 for both.
+//! Whole number division truncates towards zero by default, with_rounding() changes this to always round down
+//! as some systems require, or to round up or to the nearest number instead.
+//! Results that don't fit in an i32 give an Overflow error rather than wrapping around, roll_i64(), average_i64()
+//! and the other _i64 methods work the equation out with 64 bit numbers for equations such as "1000d1000*1000d1000".
+//!
+//! Equations typed by untrusted users can ask for a lot of work, such as "999999999d999999999".
+//! Equation::new_with_limits() refuses equations that roll too many dice, have too many sides, are too long or
+//! nest too deeply, and the statistics of exploding dice such as "2d100!>50kh1" that take too long to work out
+//! give a TooManyOutcomes error. The functions in dice_forge::roll always use the conservative Limits::untrusted().
+//!
+//! Upper case letters such as "3D6" and the operators ×, ÷ and − pasted from documents are read the same as
+//! "3d6", *, / and -. Equation::new_with_dialect() reads other notations such as the German "3W6", or can be made
+//! strict about case and operators.
+//!
+//! Arithmetic that doesn't involve any dice, such as the "2*3^2" of "1d20+2*3^2", is worked out once when
+//! the equation is compiled rather than on every roll, and plain dice added together such as "2d6+4d6" are
+//! rolled as one pool. Dividing by a constant 0 is found when compiling instead of on the first roll.
+//!
+//! Tools that need to look inside an equation can get its tree from ast(), walk it with an ast::Visitor or
+//! change it with an ast::Fold, and compile the changed tree with Equation::from_ast().
+//!
+//! An Equation can be printed, which shows it in a canonical form with only the parentheses it needs, and that
+//! text compiles back into an equal Equation. Equations can also be cloned, compared, hashed and parsed with
+//! str::parse(), two equations are equal when they compile to the same thing however they were written.
+//!
+//! With the "macros" feature the dice! macro compiles equations written in the code while the program is built,
+//! so dice!("3d6+2") is a ready made Equation and a mistake in it is a compile error.
+//!
+//! When an equation can't be compiled or rolled the error shows the equation with the part that caused the
+//! problem underlined, so "1d6 + 3d0" is shown with a "^~~" under the "3d0". The error is an
+//! InvalidExpressionError which implements std::error::Error, and tells a ParseError found while compiling apart
+//! from an EvalError found while rolling, such as dividing by 0.
+//!
 //! Here's an example of using the Dice Forge library to roll a 3d6+2 attack:
 //! ```
 //! use dice_forge::Equation;
@@ -38,7 +104,35 @@
 //! In this example the roll function will take care of all computation and retun the Result of the equation without breaking
 //! the steps up into different parts.
 
+pub mod ast;
 pub mod equation;
 pub mod roll;
+use dice_forge_core::errors;
+pub use dice_forge_core::errors::{EvalError, InvalidExpressionError, Location, ParseError};
+/// Compiles a dice equation while the program is built, needs the `macros` feature.
+///
+/// `dice!("3d6+2")` gives the same `Equation` as `Equation::new("3d6+2").unwrap()`, but a mistake in the
+/// equation is a compile error showing where it is, and nothing is parsed when the program runs.
+///
+/// # Example
+///
+/// ```
+/// use dice_forge::{dice, Equation};
+///
+/// let fireball = dice!("8d6[fire]");
+/// assert_eq!(Equation::new("8d6[fire]").unwrap(), fireball);
+/// assert_eq!((8, 48), fireball.range().unwrap());
+/// ```
+///
+/// ```compile_fail
+/// let broken = dice_forge::dice!("3d6 +* 2");
+/// ```
+#[cfg(feature = "macros")]
+pub use dice_forge_macros::dice;
 pub use equation::Equation;
-mod errors;
+/// The compiled form of an equation that the code from the `dice!` macro is built out of, not meant to be used
+/// otherwise.
+#[doc(hidden)]
+pub mod __private {
+    pub use dice_forge_core::equation::{Die, DieKind, Token};
+}
@@ -1,81 +1,1241 @@
 use crate::equation;
-use crate::equation::{Die, Equation, Token};
-use crate::errors::InvalidExpressionError;
+use crate::equation::{Equation, ExplodeKind, Function, LabeledRoll, Limits, RoundingPolicy};
+use crate::errors::{EvalError, InvalidExpressionError};
+use dice_forge_core::equation::{Die, DieKind, Token};
 use rand::Rng;
+use std::collections::{BTreeMap, HashMap};
+use std::ops::Range;
 
-pub(super) fn process(
+/// The numbers an equation can be evaluated with. `i32` and `i64` truncate division and averages towards 0
+/// while `f64` keeps any fractions until the end.
+///
+/// Arithmetic on whole numbers is checked and gives [`EvalError::Overflow`] instead of wrapping.
+pub(crate) trait Number: Copy + PartialOrd {
+    /// whether the number keeps fractions, so the argument of `floor()` and the like can be worked out with it
+    const FRACTIONS: bool;
+    fn from_i32(value: i32) -> Self;
+    /// Converts the total of a die, which is too large for `i32` when lots of large dice are rolled.
+    fn from_i64(value: i64) -> Result<Self, EvalError>;
+    /// Converts an exact average, rounding it with `rounding` when only whole numbers can be kept.
+    fn from_f64(value: f64, rounding: RoundingPolicy) -> Result<Self, EvalError>;
+    /// The whole part of the number, used where a count of dice or repeats is needed.
+    fn whole(self) -> i32;
+    /// The number with any fractions it has, used where an average is worked out.
+    fn exact(self) -> f64;
+    fn plus(self, rhs: Self) -> Result<Self, EvalError>;
+    fn minus(self, rhs: Self) -> Result<Self, EvalError>;
+    fn times(self, rhs: Self) -> Result<Self, EvalError>;
+    fn divide(self, rhs: Self, rounding: RoundingPolicy) -> Result<Self, EvalError>;
+    fn rounded(self, rounding: RoundingPolicy) -> Self;
+    /// Raises the number to `exponent`, whole numbers can't have a negative exponent.
+    fn power(self, exponent: Self) -> Result<Self, EvalError>;
+    fn abs(self) -> Result<Self, EvalError>;
+}
+/// Turns the `None` of a checked operation into an overflow error.
+fn checked<T>(value: Option<T>) -> Result<T, EvalError> {
+    value.ok_or(EvalError::Overflow(Default::default()))
+}
+impl Number for i32 {
+    const FRACTIONS: bool = false;
+    fn from_i32(value: i32) -> i32 {
+        value
+    }
+    fn from_i64(value: i64) -> Result<i32, EvalError> {
+        checked(i32::try_from(value).ok())
+    }
+    fn from_f64(value: f64, rounding: RoundingPolicy) -> Result<i32, EvalError> {
+        i64::from_f64(value, rounding).and_then(i32::from_i64)
+    }
+    fn whole(self) -> i32 {
+        self
+    }
+    fn exact(self) -> f64 {
+        self as f64
+    }
+    fn plus(self, rhs: i32) -> Result<i32, EvalError> {
+        checked(self.checked_add(rhs))
+    }
+    fn minus(self, rhs: i32) -> Result<i32, EvalError> {
+        checked(self.checked_sub(rhs))
+    }
+    fn times(self, rhs: i32) -> Result<i32, EvalError> {
+        checked(self.checked_mul(rhs))
+    }
+    fn divide(self, rhs: i32, rounding: RoundingPolicy) -> Result<i32, EvalError> {
+        rounded_divide(self as i64, rhs as i64, rounding).and_then(i32::from_i64)
+    }
+    // whole numbers have nothing to round, the argument of floor() and the like is worked out with its
+    // fractions by `Token::Rounded`
+    fn rounded(self, _rounding: RoundingPolicy) -> i32 {
+        self
+    }
+    fn power(self, exponent: i32) -> Result<i32, EvalError> {
+        if exponent < 0 {
+            return Err(EvalError::NegativeExponent(Default::default()));
+        }
+        checked(self.checked_pow(exponent as u32))
+    }
+    fn abs(self) -> Result<i32, EvalError> {
+        checked(self.checked_abs())
+    }
+}
+impl Number for i64 {
+    const FRACTIONS: bool = false;
+    fn from_i32(value: i32) -> i64 {
+        value as i64
+    }
+    fn from_i64(value: i64) -> Result<i64, EvalError> {
+        Ok(value)
+    }
+    fn from_f64(value: f64, rounding: RoundingPolicy) -> Result<i64, EvalError> {
+        let value = value.rounded(rounding);
+        // `as` saturates rather than failing, so anything outside of the range is checked first
+        if value.is_nan() || value < i64::MIN as f64 || value >= i64::MAX as f64 {
+            return Err(EvalError::Overflow(Default::default()));
+        }
+        Ok(value as i64)
+    }
+    fn whole(self) -> i32 {
+        self.clamp(i32::MIN as i64, i32::MAX as i64) as i32
+    }
+    fn exact(self) -> f64 {
+        self as f64
+    }
+    fn plus(self, rhs: i64) -> Result<i64, EvalError> {
+        checked(self.checked_add(rhs))
+    }
+    fn minus(self, rhs: i64) -> Result<i64, EvalError> {
+        checked(self.checked_sub(rhs))
+    }
+    fn times(self, rhs: i64) -> Result<i64, EvalError> {
+        checked(self.checked_mul(rhs))
+    }
+    fn divide(self, rhs: i64, rounding: RoundingPolicy) -> Result<i64, EvalError> {
+        rounded_divide(self, rhs, rounding)
+    }
+    fn rounded(self, _rounding: RoundingPolicy) -> i64 {
+        self
+    }
+    fn power(self, exponent: i64) -> Result<i64, EvalError> {
+        if exponent < 0 {
+            return Err(EvalError::NegativeExponent(Default::default()));
+        }
+        checked(self.checked_pow(checked(u32::try_from(exponent).ok())?))
+    }
+    fn abs(self) -> Result<i64, EvalError> {
+        checked(self.checked_abs())
+    }
+}
+impl Number for f64 {
+    const FRACTIONS: bool = true;
+    fn from_i32(value: i32) -> f64 {
+        value as f64
+    }
+    fn from_i64(value: i64) -> Result<f64, EvalError> {
+        Ok(value as f64)
+    }
+    fn from_f64(value: f64, _rounding: RoundingPolicy) -> Result<f64, EvalError> {
+        Ok(value)
+    }
+    fn whole(self) -> i32 {
+        self as i32
+    }
+    fn exact(self) -> f64 {
+        self
+    }
+    fn plus(self, rhs: f64) -> Result<f64, EvalError> {
+        Ok(self + rhs)
+    }
+    fn minus(self, rhs: f64) -> Result<f64, EvalError> {
+        Ok(self - rhs)
+    }
+    fn times(self, rhs: f64) -> Result<f64, EvalError> {
+        Ok(self * rhs)
+    }
+    // fractions are kept so there is nothing for the policy to round
+    fn divide(self, rhs: f64, _rounding: RoundingPolicy) -> Result<f64, EvalError> {
+        Ok(self / rhs)
+    }
+    fn rounded(self, rounding: RoundingPolicy) -> f64 {
+        match rounding {
+            RoundingPolicy::Truncate => self.trunc(),
+            RoundingPolicy::Floor => self.floor(),
+            RoundingPolicy::Ceil => self.ceil(),
+            RoundingPolicy::HalfUp => f64::round(self),
+        }
+    }
+    fn power(self, exponent: f64) -> Result<f64, EvalError> {
+        Ok(self.powf(exponent))
+    }
+    fn abs(self) -> Result<f64, EvalError> {
+        Ok(f64::abs(self))
+    }
+}
+/// Chances smaller than this are left out of the statistics of exploding dice.
+const NEGLIGIBLE: f64 = 1e-15;
+/// A value on the evaluation stack, repeat groups such as `6x(4d6kh3)` produce a list of results.
+enum Value<N> {
+    Number(N),
+    List(Vec<N>),
+}
+/// What a single roll of an equation has added up so far, the subtotal of every label and the
+/// number of dice rolled to check against the limits.
+struct Tally<N> {
+    labels: Vec<(String, N)>,
+    dice: u64,
+}
+impl<N> Tally<N> {
+    fn new() -> Tally<N> {
+        Tally {
+            labels: Vec::new(),
+            dice: 0,
+        }
+    }
+}
+impl<N: Number> Tally<N> {
+    /// Adds `value` to the subtotal for `label`.
+    fn add(&mut self, label: &str, value: N) -> Result<(), EvalError> {
+        match self.labels.iter_mut().find(|(name, _)| name == label) {
+            Some((_, subtotal)) => *subtotal = subtotal.plus(value)?,
+            None => self.labels.push((label.to_string(), value)),
+        }
+        Ok(())
+    }
+}
+pub(super) fn process<N: Number>(
     equation: &Equation,
     ty: equation::RollType,
-) -> Result<i32, InvalidExpressionError> {
-    let mut stack: Vec<i32> = Vec::with_capacity(equation.compiled_equation.len());
-    for token in &equation.compiled_equation {
-        match *token {
-            Token::Operand(value) => stack.push(value as i32),
-            Token::Dice(die) => match ty {
-                equation::RollType::Default => stack.push(roll_die(die)),
-                equation::RollType::Low => stack.push(die.number as i32),
-                equation::RollType::High => stack.push((die.number * die.sides) as i32),
-                equation::RollType::Average => {
-                    stack.push((die.number as f32 * (die.sides as f32 / 2.0 + 0.5)) as i32)
+    vars: &HashMap<&str, i32>,
+) -> Result<N, InvalidExpressionError> {
+    let everything = 0..equation.compiled_equation.len();
+    match evaluate(everything, equation, &ty, vars, &mut Tally::new()) {
+        Ok(Value::Number(value)) => Ok(value),
+        Ok(Value::List(_)) => Err(EvalError::ListAsNumber(Default::default())
+            .at(0..equation.input.len())
+            .in_input(&equation.input)
+            .into()),
+        Err(err) => Err(err.in_input(&equation.input).into()),
+    }
+}
+/// Evaluates the equation along with the subtotal of every label.
+pub(super) fn process_labeled(
+    equation: &Equation,
+    ty: equation::RollType,
+    vars: &HashMap<&str, i32>,
+) -> Result<LabeledRoll, InvalidExpressionError> {
+    let mut tally = Tally::new();
+    let everything = 0..equation.compiled_equation.len();
+    match evaluate(everything, equation, &ty, vars, &mut tally) {
+        Ok(Value::Number(total)) => Ok(LabeledRoll {
+            total,
+            labels: tally.labels,
+        }),
+        Ok(Value::List(_)) => Err(EvalError::ListAsNumber(Default::default())
+            .at(0..equation.input.len())
+            .in_input(&equation.input)
+            .into()),
+        Err(err) => Err(err.in_input(&equation.input).into()),
+    }
+}
+/// Evaluates the equation returning every result of a repeat group, or a single result otherwise.
+pub(super) fn process_many(
+    equation: &Equation,
+    ty: equation::RollType,
+    vars: &HashMap<&str, i32>,
+) -> Result<Vec<i32>, InvalidExpressionError> {
+    let everything = 0..equation.compiled_equation.len();
+    match evaluate(everything, equation, &ty, vars, &mut Tally::new()) {
+        Ok(Value::Number(value)) => Ok(vec![value]),
+        Ok(Value::List(values)) => Ok(values),
+        Err(err) => Err(err.in_input(&equation.input).into()),
+    }
+}
+fn pop_number<N: Number>(stack: &mut Vec<Value<N>>) -> Result<N, EvalError> {
+    match stack.pop() {
+        Some(Value::Number(value)) => Ok(value),
+        Some(Value::List(_)) => Err(EvalError::ListAsNumber(Default::default())),
+        None => Err(EvalError::InvalidExpression(Default::default())),
+    }
+}
+/// A repeat group, rounding function or conditional whose tokens are being evaluated, kept on a stack rather than
+/// evaluated by recursing so deeply nested equations can't run out of stack. Each is started by the token at `at`
+/// and leaves its result on top of the stack once the tokens up to `end` have been evaluated.
+enum Nested<N> {
+    /// the body from `start` is evaluated `left` more times, giving a list of the results
+    Repeat {
+        at: usize,
+        start: usize,
+        end: usize,
+        left: i32,
+        values: Vec<N>,
+    },
+    /// the argument of a rounding function, when `N` keeps the fractions for it
+    Rounded {
+        at: usize,
+        end: usize,
+        rounding: RoundingPolicy,
+    },
+    /// both branches of a conditional whose condition could go either way, for the lowest or highest of them. The
+    /// true branch ends at the `Jump` at `jump`, and each branch is checked against the dice limit on its own
+    /// starting from `dice`
+    Branches {
+        at: usize,
+        jump: usize,
+        end: usize,
+        condition: bool,
+        dice: u64,
+        then: Option<(Value<N>, u64)>,
+    },
+}
+impl<N: Number> Nested<N> {
+    fn at(&self) -> usize {
+        match self {
+            Nested::Repeat { at, .. }
+            | Nested::Rounded { at, .. }
+            | Nested::Branches { at, .. } => *at,
+        }
+    }
+    /// Checks if everything up to `index` has been evaluated, for the true branch of a conditional that is up to its
+    /// jump.
+    fn reached(&self, index: usize) -> bool {
+        match self {
+            Nested::Repeat { end, .. } | Nested::Rounded { end, .. } => index == *end,
+            Nested::Branches {
+                jump, end, then, ..
+            } => match then {
+                None => index == *jump,
+                Some(_) => index == *end,
+            },
+        }
+    }
+}
+/// Evaluates the tokens of the equation in the range `tokens`.
+fn evaluate<N: Number>(
+    tokens: Range<usize>,
+    equation: &Equation,
+    ty: &equation::RollType,
+    vars: &HashMap<&str, i32>,
+    tally: &mut Tally<N>,
+) -> Result<Value<N>, EvalError> {
+    let mut stack: Vec<Value<N>> = Vec::with_capacity(tokens.len());
+    let mut nested: Vec<Nested<N>> = Vec::new();
+    let mut index = tokens.start;
+    loop {
+        // several can end at the same token, such as "floor(floor(1/2))"
+        while let Some(at) = nested
+            .last()
+            .filter(|last| last.reached(index))
+            .map(Nested::at)
+        {
+            finish_nested(&mut index, (&mut stack, &mut nested), ty, tally)
+                .map_err(|err| err.at(equation.spans[at].clone()))?;
+        }
+        if index >= tokens.end {
+            break;
+        }
+        let at = index;
+        index += 1;
+        evaluate_token(
+            at,
+            &mut index,
+            (&mut stack, &mut nested),
+            equation,
+            ty,
+            vars,
+            tally,
+        )
+        .map_err(|err| err.at(equation.spans[at].clone()))?;
+    }
+    stack
+        .pop()
+        .ok_or_else(|| EvalError::InvalidExpression(Default::default()).at(0..equation.input.len()))
+}
+/// Takes the result of the repeat group, rounding function or conditional on top of `nested` once its tokens up to
+/// `index` have been evaluated, moving `index` back to the start of a body that is repeated again or on to the false
+/// branch of a conditional.
+fn finish_nested<N: Number>(
+    index: &mut usize,
+    (stack, nested): (&mut Vec<Value<N>>, &mut Vec<Nested<N>>),
+    ty: &equation::RollType,
+    tally: &mut Tally<N>,
+) -> Result<(), EvalError> {
+    let value = stack
+        .pop()
+        .ok_or(EvalError::InvalidExpression(Default::default()))?;
+    let Some(last) = nested.last_mut() else {
+        return Err(EvalError::InvalidExpression(Default::default()));
+    };
+    match last {
+        Nested::Repeat {
+            start,
+            left,
+            values,
+            ..
+        } => {
+            match value {
+                Value::Number(value) => values.push(value),
+                Value::List(_) => return Err(EvalError::ListAsNumber(Default::default())),
+            }
+            *left -= 1;
+            if *left > 0 {
+                *index = *start;
+                return Ok(());
+            }
+            let values = std::mem::take(values);
+            stack.push(Value::List(values));
+        }
+        Nested::Rounded { rounding, .. } => match value {
+            Value::Number(value) => stack.push(Value::Number(value.rounded(*rounding))),
+            Value::List(_) => return Err(EvalError::ListAsNumber(Default::default())),
+        },
+        Nested::Branches {
+            jump,
+            dice,
+            then: then @ None,
+            ..
+        } => {
+            *then = Some((value, tally.dice));
+            tally.dice = *dice;
+            // past the jump over the false branch
+            *index = *jump + 1;
+            return Ok(());
+        }
+        Nested::Branches {
+            condition,
+            then: Some((then, then_dice)),
+            ..
+        } => {
+            tally.dice = tally.dice.max(*then_dice);
+            let further = |lhs: &N, rhs: &N| match ty {
+                equation::RollType::High => rhs < lhs,
+                _ => lhs < rhs,
+            };
+            // a list can't be compared, so the branch `condition` picks is used
+            let then = std::mem::replace(then, Value::List(Vec::new()));
+            stack.push(match (then, value) {
+                (Value::Number(lhs), Value::Number(rhs)) if further(&lhs, &rhs) => {
+                    Value::Number(lhs)
                 }
-                equation::RollType::Emphasis => {
-                    let i = (die.number as f32 * (die.sides as f32 / 2.0 + 0.5)) as i32;
-                    let a = (roll_die(die)-i).abs();
-                    let b = (roll_die(die)-i).abs();
-                    stack.push(if a > b {a} else {b});
+                (Value::Number(_), Value::Number(rhs)) => Value::Number(rhs),
+                (then, _) if *condition => then,
+                (_, otherwise) => otherwise,
+            });
+        }
+    }
+    nested.pop();
+    Ok(())
+}
+/// Evaluates the token at `at`, `index` is the next token to evaluate and is moved past any tokens that are skipped.
+/// A repeat group, rounding function or conditional that needs its tokens evaluated is pushed onto `nested`.
+fn evaluate_token<N: Number>(
+    at: usize,
+    index: &mut usize,
+    (stack, nested): (&mut Vec<Value<N>>, &mut Vec<Nested<N>>),
+    equation: &Equation,
+    ty: &equation::RollType,
+    vars: &HashMap<&str, i32>,
+    tally: &mut Tally<N>,
+) -> Result<(), EvalError> {
+    let zero = N::from_i32(0);
+    match &equation.compiled_equation[at] {
+        Token::Operand(value) => stack.push(Value::Number(N::from_i32(*value as i32))),
+        Token::Dice(die) => {
+            count_dice(die, ty, equation, tally)?;
+            stack.push(Value::Number(evaluate_die(die, ty, equation)?));
+        }
+        Token::DynamicDice { die, sides } => {
+            let exact_sides = if *sides {
+                Some(pop_number(stack)?)
+            } else {
+                None
+            };
+            let exact_count = pop_number(stack)?;
+            let (count, sides) = (exact_count.whole(), exact_sides.map(N::whole));
+            if count <= 0 || sides.is_some_and(|sides| sides <= 0) {
+                return Err(EvalError::InvalidDie(Default::default()));
+            }
+            if let equation::RollType::Average = ty {
+                let average = average_dynamic_dice(
+                    die,
+                    exact_count.exact(),
+                    exact_sides.map(N::exact),
+                    equation,
+                    tally,
+                )?;
+                stack.push(Value::Number(N::from_f64(average, equation.rounding)?));
+                return Ok(());
+            }
+            let mut die = die.clone();
+            die.number = count as u32;
+            if let Some(sides) = sides {
+                die.kind = DieKind::Standard(sides as u32);
+            }
+            count_dice(&die, ty, equation, tally)?;
+            stack.push(Value::Number(evaluate_die(&die, ty, equation)?));
+        }
+        Token::Variable(name) => match vars.get(name.as_str()) {
+            Some(&value) => stack.push(Value::Number(N::from_i32(value))),
+            None => return Err(EvalError::UnboundVariable(name.clone(), Default::default())),
+        },
+        Token::Label(label) => {
+            let value = match stack.last() {
+                Some(Value::Number(value)) => *value,
+                Some(Value::List(values)) => values.iter().try_fold(zero, |sum, &v| sum.plus(v))?,
+                None => return Err(EvalError::InvalidExpression(Default::default())),
+            };
+            tally.add(label, value)?;
+        }
+        Token::Repeat(length) => {
+            let count = pop_number(stack)?.whole();
+            if count < 0 {
+                return Err(EvalError::InvalidExpression(Default::default()));
+            }
+            if count as u32 > equation.limits.max_repeats {
+                return Err(EvalError::TooManyRepeats(Default::default()));
+            }
+            if count == 0 {
+                stack.push(Value::List(Vec::new()));
+                *index += length;
+                return Ok(());
+            }
+            // grown as the results come in, the count can be far more than fits in memory when the limit allows it
+            nested.push(Nested::Repeat {
+                at,
+                start: *index,
+                end: *index + length,
+                left: count,
+                values: Vec::new(),
+            });
+        }
+        Token::Plus => {
+            let rhs = pop_number(stack)?;
+            let lhs = pop_number(stack)?;
+            stack.push(Value::Number(lhs.plus(rhs)?));
+        }
+        Token::Minus => {
+            let rhs = pop_number(stack)?;
+            let lhs = pop_number(stack)?;
+            stack.push(Value::Number(lhs.minus(rhs)?));
+        }
+        Token::Times => {
+            let rhs = pop_number(stack)?;
+            let lhs = pop_number(stack)?;
+            stack.push(Value::Number(lhs.times(rhs)?));
+        }
+        Token::Divide => {
+            let rhs = pop_number(stack)?;
+            let lhs = pop_number(stack)?;
+            if rhs == zero {
+                return Err(EvalError::DivideByZero(Default::default()));
+            }
+            stack.push(Value::Number(lhs.divide(rhs, equation.rounding)?));
+        }
+        // the argument keeps its fractions so that it is rounded once as a whole, rather than every
+        // division in it truncating
+        Token::Rounded(rounding, length) if N::FRACTIONS => nested.push(Nested::Rounded {
+            at,
+            end: *index + length,
+            rounding: *rounding,
+        }),
+        Token::Rounded(rounding, length) => {
+            let body = *index..*index + length;
+            let mut exact = Tally {
+                labels: Vec::new(),
+                dice: tally.dice,
+            };
+            let value = match evaluate::<f64>(body, equation, ty, vars, &mut exact)? {
+                Value::Number(value) => value.rounded(*rounding),
+                Value::List(_) => return Err(EvalError::ListAsNumber(Default::default())),
+            };
+            tally.dice = exact.dice;
+            for (label, subtotal) in exact.labels {
+                tally.add(&label, N::from_f64(subtotal, equation.rounding)?)?;
+            }
+            stack.push(Value::Number(N::from_f64(value, *rounding)?));
+            *index += length;
+        }
+        Token::Compare(op) => {
+            let rhs = pop_number(stack)?;
+            let lhs = pop_number(stack)?;
+            // the lowest or highest operands say nothing about which way a comparison of dice can go, such as
+            // "1d6 != 3" which is false on a 3, so the lowest it can be is false and the highest true
+            let truth = match ty {
+                equation::RollType::Low => false,
+                equation::RollType::High => true,
+                _ => op.apply(lhs, rhs),
+            };
+            stack.push(Value::Number(N::from_i32(truth as i32)));
+        }
+        Token::And(skip) => {
+            let lhs = pop_number(stack)?;
+            if lhs == zero {
+                stack.push(Value::Number(zero));
+                *index += skip;
+            }
+        }
+        Token::Or(skip) => {
+            let lhs = pop_number(stack)?;
+            if lhs != zero {
+                stack.push(Value::Number(N::from_i32(1)));
+                *index += skip;
+            }
+        }
+        Token::Truthy => {
+            let value = pop_number(stack)?;
+            stack.push(Value::Number(N::from_i32((value != zero) as i32)));
+        }
+        Token::JumpIfFalse(skip) => {
+            let condition = pop_number(stack)?;
+            let constant = matches!(
+                equation.compiled_equation.get(at.wrapping_sub(1)),
+                Some(Token::Operand(_))
+            );
+            match ty {
+                // a condition involving dice could go either way
+                equation::RollType::Low | equation::RollType::High if !constant => {
+                    // the true branch ends with the jump over the false branch
+                    let jump = *index + skip - 1;
+                    let rest = match equation.compiled_equation.get(jump) {
+                        Some(Token::Jump(rest)) => *rest,
+                        _ => return Err(EvalError::InvalidExpression(Default::default())),
+                    };
+                    nested.push(Nested::Branches {
+                        at,
+                        jump,
+                        end: jump + 1 + rest,
+                        condition: condition != zero,
+                        dice: tally.dice,
+                        then: None,
+                    });
                 }
-            },
-            Token::Plus => {
-                let rhs = stack.pop().unwrap();
-                let lhs = stack.pop().unwrap();
-                stack.push(lhs + rhs);
-            }
-            Token::Minus => {
-                let rhs = stack.pop().unwrap();
-                let lhs = stack.pop().unwrap();
-                stack.push(lhs - rhs);
-            }
-            Token::Times => {
-                let rhs = stack.pop().unwrap();
-                let lhs = stack.pop().unwrap();
-                stack.push(lhs * rhs);
-            }
-            Token::Divide => {
-                let rhs = stack.pop().unwrap();
-                let lhs = stack.pop().unwrap();
-                if rhs == 0 {
-                    return Err(InvalidExpressionError::DivideByZero);
+                _ if condition == zero => *index += skip,
+                _ => {}
+            }
+        }
+        Token::Jump(skip) => *index += skip,
+        Token::Function(function, count) => {
+            let start = stack.len().saturating_sub(*count as usize);
+            let args = stack.split_off(start);
+            stack.push(Value::Number(call_function(*function, args)?));
+        }
+        Token::Exponent => {
+            let ex = pop_number(stack)?;
+            let d = pop_number(stack)?;
+            stack.push(Value::Number(d.power(ex)?));
+        }
+        _ => {}
+    }
+    Ok(())
+}
+/// Works out the average of dice whose number and sides are the averages `count` and `sides` of other dice,
+/// rather than cutting the fractions off of them. The averages of the whole numbers either side are weighed by how
+/// close they are, which is exact for dice without modifiers as their average grows in step with both.
+fn average_dynamic_dice<N>(
+    die: &Die,
+    count: f64,
+    sides: Option<f64>,
+    equation: &Equation,
+    tally: &mut Tally<N>,
+) -> Result<f64, EvalError> {
+    let either_side = |value: f64| {
+        let fraction = value - value.floor();
+        [(value.floor(), 1.0 - fraction), (value.ceil(), fraction)]
+            .into_iter()
+            .filter(|&(_, weight)| weight > 0.0)
+    };
+    let with = |count: f64, sides: Option<f64>| {
+        let mut die = die.clone();
+        die.number = count as u32;
+        if let Some(sides) = sides {
+            die.kind = DieKind::Standard(sides as u32);
+        }
+        die
+    };
+    // the most dice that could be rolled are counted against the limits
+    count_dice(
+        &with(count.ceil(), sides.map(f64::ceil)),
+        &equation::RollType::Average,
+        equation,
+        tally,
+    )?;
+    let mut average = 0.0;
+    for (count, count_weight) in either_side(count) {
+        let corners: Vec<(Option<f64>, f64)> = match sides {
+            Some(sides) => either_side(sides).map(|(s, w)| (Some(s), w)).collect(),
+            None => vec![(None, 1.0)],
+        };
+        for (sides, sides_weight) in corners {
+            let die = with(count, sides);
+            let value: f64 = evaluate_die(&die, &equation::RollType::Average, equation)?;
+            average += value * count_weight * sides_weight;
+        }
+    }
+    Ok(average)
+}
+/// Adds the dice about to be rolled to the tally, checking them against the equation's limits first.
+fn count_dice<N>(
+    die: &Die,
+    ty: &equation::RollType,
+    equation: &Equation,
+    tally: &mut Tally<N>,
+) -> Result<(), EvalError> {
+    if die.too_many_sides(&equation.limits) {
+        return Err(EvalError::TooManySides(Default::default()));
+    }
+    // emphasis rolls every die twice to pick the result furthest from the average
+    let rolls = match ty {
+        equation::RollType::Emphasis => 2,
+        _ => 1,
+    };
+    tally.dice = tally.dice.saturating_add(die.number as u64 * rolls);
+    if tally.dice > equation.limits.max_dice {
+        return Err(EvalError::TooManyDice(Default::default()));
+    }
+    Ok(())
+}
+/// Divides `lhs` by `rhs` rounding the result instead of truncating it towards 0.
+pub(crate) fn rounded_divide(
+    lhs: i64,
+    rhs: i64,
+    rounding: RoundingPolicy,
+) -> Result<i64, EvalError> {
+    // only the smallest number divided by -1 can overflow
+    let quotient = checked(lhs.checked_div(rhs))?;
+    let remainder = lhs % rhs;
+    if remainder == 0 {
+        return Ok(quotient);
+    }
+    // the truncated quotient is 1 too high when the exact answer is negative and 1 too low when positive
+    let step = if (lhs < 0) == (rhs < 0) { 1 } else { -1 };
+    // a remainder means the quotient is smaller than `lhs`, so stepping it can't overflow
+    Ok(match rounding {
+        RoundingPolicy::Floor if step < 0 => quotient - 1,
+        RoundingPolicy::Ceil if step > 0 => quotient + 1,
+        RoundingPolicy::HalfUp
+            if remainder.unsigned_abs() >= rhs.unsigned_abs() - remainder.unsigned_abs() =>
+        {
+            quotient + step
+        }
+        _ => quotient,
+    })
+}
+fn call_function<N: Number>(function: Function, args: Vec<Value<N>>) -> Result<N, EvalError> {
+    // min, max and sum take every value out of a list, everything else needs single numbers
+    let mut numbers = Vec::with_capacity(args.len());
+    for arg in args {
+        match arg {
+            Value::Number(value) => numbers.push(value),
+            Value::List(values)
+                if matches!(function, Function::Min | Function::Max | Function::Sum) =>
+            {
+                numbers.extend(values)
+            }
+            Value::List(_) => return Err(EvalError::ListAsNumber(Default::default())),
+        }
+    }
+    let zero = N::from_i32(0);
+    let min = |a: N, b: N| if b < a { b } else { a };
+    let max = |a: N, b: N| if b > a { b } else { a };
+    Ok(match function {
+        Function::Min => numbers.into_iter().reduce(min).unwrap_or(zero),
+        Function::Max => numbers.into_iter().reduce(max).unwrap_or(zero),
+        Function::Sum => numbers.into_iter().try_fold(zero, |sum, n| sum.plus(n))?,
+        Function::Abs => numbers[0].abs()?,
+        Function::Clamp => min(max(numbers[0], numbers[1]), numbers[2]),
+        Function::Floor => numbers[0].rounded(RoundingPolicy::Floor),
+        Function::Ceil => numbers[0].rounded(RoundingPolicy::Ceil),
+        Function::Round => numbers[0].rounded(RoundingPolicy::HalfUp),
+    })
+}
+fn evaluate_die<N: Number>(
+    die: &Die,
+    ty: &equation::RollType,
+    equation: &Equation,
+) -> Result<N, EvalError> {
+    let explosion_limit = equation.limits.max_explosions;
+    check_outcomes(die, ty, &equation.limits)?;
+    Ok(match ty {
+        equation::RollType::Default => N::from_i64(roll_die(die, equation)?)?,
+        equation::RollType::Low => {
+            let lowest = lowest_face(die, explosion_limit);
+            N::from_i64(checked((die.kept() as i64).checked_mul(lowest))?)?
+        }
+        equation::RollType::High => {
+            let highest = checked(highest_face(die, explosion_limit))?;
+            N::from_i64(checked((die.kept() as i64).checked_mul(highest))?)?
+        }
+        equation::RollType::Average => {
+            N::from_f64(average_die(die, explosion_limit), equation.rounding)?
+        }
+        equation::RollType::Emphasis => {
+            let i = N::from_f64(average_die(die, explosion_limit), equation.rounding)?;
+            let a = N::from_i64(roll_die(die, equation)?)?.minus(i)?.abs()?;
+            let b = N::from_i64(roll_die(die, equation)?)?.minus(i)?.abs()?;
+            if a > b {
+                a
+            } else {
+                b
+            }
+        }
+    })
+}
+/// Checks the statistics of the die fit in the limits, they go through every total a die can add up to in order to
+/// work out dice that are kept or dropped and successes counted on compounded dice.
+fn check_outcomes(die: &Die, ty: &equation::RollType, limits: &Limits) -> Result<(), EvalError> {
+    let compound = die.explode.map(|e| e.kind) == Some(ExplodeKind::Compound);
+    let pool = die.success.is_some() && compound && limits.max_explosions > 0;
+    let needed = match ty {
+        equation::RollType::Default => false,
+        equation::RollType::Low | equation::RollType::High => pool,
+        equation::RollType::Average | equation::RollType::Emphasis => pool || die.keep.is_some(),
+    };
+    if !needed {
+        return Ok(());
+    }
+    let runs = face_runs(die);
+    let faces: u64 = runs.iter().map(|run| run.len() as u64).sum();
+    let q = explode_chance(die, &runs);
+    // chains of explosions less likely than `NEGLIGIBLE` aren't followed
+    let followed = match (die.explode.is_some() && limits.max_explosions > 0, q < 1.0) {
+        (false, _) => 1,
+        (true, true) => {
+            ((NEGLIGIBLE.ln() / q.ln()).ceil() as u64).min(limits.max_explosions as u64)
+        }
+        (true, false) => limits.max_explosions as u64,
+    };
+    if faces.saturating_mul(followed) > limits.max_outcomes {
+        return Err(EvalError::TooManyOutcomes(Default::default()));
+    }
+    Ok(())
+}
+/// Rolls the die, totals are kept as `i64` so the dice themselves can only overflow once explosions
+/// have been compounded onto them more times than `i64` can hold.
+fn roll_die(die: &Die, equation: &Equation) -> Result<i64, EvalError> {
+    let mut rng = rand::thread_rng();
+    if die.is_simple() {
+        let mut current: i64 = 0;
+        for _n in 0..die.number {
+            current = checked(current.checked_add(random_face(die, &mut rng)))?;
+        }
+        return Ok(current);
+    }
+    let mut roll_face = || -> Result<i64, EvalError> {
+        let mut face = random_face(die, &mut rng);
+        if let Some(reroll) = die.reroll {
+            let mut rerolls = 0;
+            while reroll.compare.matches(face) {
+                if reroll.once && rerolls == 1 {
+                    break;
                 }
-                stack.push(lhs / rhs);
-            }
-            //cant handle fractional exponents as I am too dumb to know how to do them trunkates the desimal
-            Token::Exponent => {
-                let ex = stack.pop().unwrap();
-                let d = stack.pop().unwrap();
-                match ex {
-                    0 => stack.push(1),
-                    1 => stack.push(d),
-                    _ => {
-                        let mut b: i32 = 1;
-                        for _n in 0..ex {
-                            b *= d;
+                if rerolls == equation.limits.max_rerolls {
+                    return Err(EvalError::RerollLimit(Default::default()));
+                }
+                face = random_face(die, &mut rng);
+                rerolls += 1;
+            }
+        }
+        Ok(face)
+    };
+    let mut rolls: Vec<i64> = Vec::with_capacity(die.number as usize);
+    for _n in 0..die.number {
+        let mut face = roll_face()?;
+        let mut value = face;
+        let mut explosions = 0;
+        while explosions < equation.limits.max_explosions && die.explodes(face) {
+            face = roll_face()?;
+            explosions += 1;
+            match die.explode.map(|e| e.kind) {
+                Some(ExplodeKind::Compound) => value = checked(value.checked_add(face))?,
+                Some(ExplodeKind::Penetrate) => {
+                    rolls.push(value);
+                    value = face - 1;
+                }
+                _ => {
+                    rolls.push(value);
+                    value = face;
+                }
+            }
+        }
+        rolls.push(value);
+    }
+    if die.keep.is_some() {
+        rolls.sort_unstable();
+        let (start, end) = die.kept_range(rolls.len() as u32);
+        rolls.truncate(end as usize);
+        rolls.drain(..start as usize);
+    }
+    if die.success.is_some() {
+        return Ok(rolls.iter().map(|&value| die.score(value)).sum());
+    }
+    rolls
+        .into_iter()
+        .try_fold(0, |sum: i64, value| checked(sum.checked_add(value)))
+}
+fn random_face(die: &Die, rng: &mut impl Rng) -> i64 {
+    match &die.kind {
+        DieKind::Custom(faces) => faces[rng.gen_range(0..faces.len())] as i64,
+        _ => rng.gen_range(die.min_face()..=die.max_face()) as i64,
+    }
+}
+/// Consecutive faces of a die that are equally likely and that every modifier of the die treats the same, so that
+/// dice with a huge number of sides can be worked out without going through each face.
+#[derive(Debug, Clone, Copy)]
+struct Run {
+    first: i64,
+    last: i64,
+    /// the chance of a single roll landing on each face of the run
+    chance: f64,
+}
+impl Run {
+    fn len(&self) -> i64 {
+        self.last - self.first + 1
+    }
+    /// Splits the run into the faces below, at and above `at`, leaving out any that are empty.
+    fn split(self, at: i64) -> impl Iterator<Item = Run> {
+        let parts = [
+            (self.first, self.last.min(at - 1)),
+            (self.first.max(at), self.last.min(at)),
+            (self.first.max(at + 1), self.last),
+        ];
+        parts
+            .into_iter()
+            .filter(|(first, last)| first <= last)
+            .map(move |(first, last)| Run {
+                first,
+                last,
+                ..self
+            })
+    }
+}
+/// The faces a single roll of the die can land on once rerolls are taken into account, from lowest to highest.
+///
+/// The runs are split at every compare point of the die and at the face 1 above it, which a penetrating
+/// explosion lowers onto it, so each face of a run rerolls, explodes and scores the same.
+fn face_runs(die: &Die) -> Vec<Run> {
+    let mut runs = match &die.kind {
+        // the sides aren't known until they are rolled
+        DieKind::Standard(0) => return Vec::new(),
+        DieKind::Custom(faces) => {
+            let chance = 1.0 / faces.len() as f64;
+            let face = |&face: &i32| Run {
+                first: face as i64,
+                last: face as i64,
+                chance,
+            };
+            faces.iter().map(face).collect()
+        }
+        _ => {
+            let (first, last) = (die.min_face() as i64, die.max_face() as i64);
+            let chance = 1.0 / (last - first + 1) as f64;
+            vec![Run {
+                first,
+                last,
+                chance,
+            }]
+        }
+    };
+    let compares = [
+        die.reroll.map(|reroll| reroll.compare),
+        die.explode.and_then(|explode| explode.compare),
+        die.success,
+        die.failure,
+    ];
+    let mut points: Vec<i64> = compares.iter().flatten().map(|c| c.value as i64).collect();
+    points.push(die.max_face() as i64);
+    for point in points {
+        for at in [point, point + 1] {
+            runs = runs.into_iter().flat_map(|run| run.split(at)).collect();
+        }
+    }
+    if let Some(reroll) = die.reroll {
+        let chance = 1.0 / runs.iter().map(Run::len).sum::<i64>() as f64;
+        let matched = |run: &&Run| reroll.compare.matches(run.first);
+        let rerolled = runs.iter().filter(matched).map(Run::len).sum::<i64>() as f64;
+        let kept = 1.0 / chance - rerolled;
+        for run in &mut runs {
+            run.chance = match (reroll.once, reroll.compare.matches(run.first)) {
+                (true, true) => rerolled * chance * chance,
+                (true, false) => chance + rerolled * chance * chance,
+                (false, true) => 0.0,
+                (false, false) => 1.0 / kept,
+            };
+        }
+        runs.retain(|run| run.chance > 0.0);
+    }
+    runs
+}
+/// Probability of a single roll of the die landing on each face once rerolls are taken into account.
+///
+/// This goes through every face, [`check_outcomes`] keeps it within the limits.
+fn face_chances(die: &Die) -> Vec<(i64, f64)> {
+    let faces = |run: Run| (run.first..=run.last).map(move |face| (face, run.chance));
+    face_runs(die).into_iter().flat_map(faces).collect()
+}
+/// The chance of a single roll of the die exploding.
+fn explode_chance(die: &Die, runs: &[Run]) -> f64 {
+    runs.iter()
+        .filter(|run| die.explodes(run.first))
+        .map(|run| run.chance * run.len() as f64)
+        .sum()
+}
+/// The lowest value a single die can contribute, including any explosions.
+///
+/// This is usually the lowest face that doesn't explode, but faces below 0 can make a chain of explosions lower.
+fn lowest_face(die: &Die, explosion_limit: u32) -> i64 {
+    if die.is_simple() {
+        return die.min_face() as i64;
+    }
+    let compound = die.explode.map(|e| e.kind) == Some(ExplodeKind::Compound);
+    // the successes of a compounded die depend on the whole chain rather than each roll
+    if die.success.is_some() && compound && explosion_limit > 0 {
+        return die_distribution(die, explosion_limit)
+            .first()
+            .map_or(0, |&(score, _)| score);
+    }
+    // every face of a run is worth the same or more than the one before it, so the lowest is the first
+    let faces: Vec<i64> = face_runs(die).iter().map(|run| run.first).collect();
+    if die.explode.is_none() || explosion_limit == 0 {
+        return faces.iter().map(|&f| roll_value(die, f)).min().unwrap_or(0);
+    }
+    let penalty = explosion_penalty(die);
+    // worst value of a roll showing `face` when the dice it explodes into are worth at least `extra`
+    let worst = |face: i64, penalty: i64, extra: i64| {
+        let value = roll_value(die, face - penalty);
+        match die.explodes(face) {
+            true => value.saturating_add(extra),
+            false => value,
+        }
+    };
+    let mut worst_extra = faces
+        .iter()
+        .map(|&f| roll_value(die, f - penalty))
+        .min()
+        .unwrap_or(0);
+    for _n in 1..explosion_limit {
+        worst_extra = faces
+            .iter()
+            .map(|&f| worst(f, penalty, worst_extra))
+            .min()
+            .unwrap_or(0);
+    }
+    faces
+        .iter()
+        .map(|&f| worst(f, 0, worst_extra))
+        .min()
+        .unwrap_or(0)
+}
+/// The highest value a single die can contribute once it has exploded as many times as it is allowed,
+/// or `None` if a long enough chain of explosions could add up to more than `i64` can hold.
+fn highest_face(die: &Die, explosion_limit: u32) -> Option<i64> {
+    if die.is_simple() {
+        return Some(die.max_face() as i64);
+    }
+    let runs = face_runs(die);
+    let compound = die.explode.map(|e| e.kind) == Some(ExplodeKind::Compound);
+    // the successes of a compounded die depend on the whole chain rather than each roll
+    if die.success.is_some() && compound && explosion_limit > 0 {
+        return Some(
+            die_distribution(die, explosion_limit)
+                .last()
+                .map_or(0, |&(score, _)| score),
+        );
+    }
+    if die.success.is_some() && (die.explode.is_none() || explosion_limit == 0) {
+        return Some(
+            runs.iter()
+                .map(|run| die.score(run.first))
+                .max()
+                .unwrap_or(0),
+        );
+    }
+    // every face of a run is worth the same or less than the one after it, so the highest is the last
+    let faces: Vec<i64> = runs.iter().map(|run| run.last).collect();
+    if explosion_limit == 0 {
+        return Some(faces.iter().copied().max().unwrap_or(0));
+    }
+    let penalty = explosion_penalty(die);
+    // best value of a roll showing `face` when the dice it explodes into are worth at most `extra`
+    let best = |face: i64, penalty: i64, extra: i64| {
+        if die.explodes(face) {
+            roll_value(die, face - penalty).checked_add(extra)
+        } else {
+            Some(roll_value(die, face - penalty))
+        }
+    };
+    // best value of an extra die rolled after an explosion with `n` explosions left
+    let mut best_extra = faces
+        .iter()
+        .map(|&f| roll_value(die, f - penalty))
+        .max()
+        .unwrap_or(0);
+    for _n in 1..explosion_limit {
+        best_extra = faces
+            .iter()
+            .map(|&f| best(f, penalty, best_extra))
+            .collect::<Option<Vec<_>>>()?
+            .into_iter()
+            .max()
+            .unwrap_or(0);
+    }
+    faces
+        .iter()
+        .map(|&f| best(f, 0, best_extra))
+        .collect::<Option<Vec<_>>>()
+        .map(|values| values.into_iter().max().unwrap_or(0))
+}
+/// Extra dice rolled by a penetrating explosion have 1 subtracted from them.
+fn explosion_penalty(die: &Die) -> i64 {
+    match die.explode.map(|e| e.kind) {
+        Some(ExplodeKind::Penetrate) => 1,
+        _ => 0,
+    }
+}
+/// What a single roll adds to the die's result, which for a success counting pool where every
+/// explosion is its own die is the score of that roll rather than its face.
+fn roll_value(die: &Die, face: i64) -> i64 {
+    match die.explode.map(|e| e.kind) {
+        Some(ExplodeKind::Compound) => face,
+        _ if die.success.is_some() => die.score(face),
+        _ => face,
+    }
+}
+/// Probability of a single die adding up to each possible value, sorted from lowest to highest.
+///
+/// An exploding die is treated as the total of its whole chain of explosions, which is exact for
+/// sums but only an approximation when standard or penetrating explosions are combined with keep
+/// or drop modifiers. Chains whose chance is too small to matter are not followed any further.
+/// For success counting pools the values are the number of successes rather than the faces.
+fn die_distribution(die: &Die, explosion_limit: u32) -> Vec<(i64, f64)> {
+    let faces = face_chances(die);
+    let mut distribution: BTreeMap<i64, f64> = BTreeMap::new();
+    if die.explode.is_none() || explosion_limit == 0 {
+        for (face, p) in faces {
+            *distribution.entry(roll_value(die, face)).or_insert(0.0) += p;
+        }
+    } else {
+        let penalty = explosion_penalty(die);
+        let chain = |penalty: i64, next: &BTreeMap<i64, f64>| {
+            let mut distribution = BTreeMap::new();
+            for &(face, chance) in &faces {
+                let value = roll_value(die, face - penalty);
+                if die.explodes(face) {
+                    for (&rest, &p) in next {
+                        let p = p * chance;
+                        if p > NEGLIGIBLE {
+                            *distribution.entry(value + rest).or_insert(0.0) += p;
                         }
-                        stack.push(b);
                     }
+                } else {
+                    *distribution.entry(value).or_insert(0.0) += chance;
                 }
             }
-            _ => {}
+            distribution
+        };
+        let mut extra = BTreeMap::new();
+        for &(f, p) in &faces {
+            *extra.entry(roll_value(die, f - penalty)).or_insert(0.0) += p;
+        }
+        for _n in 1..explosion_limit {
+            extra = chain(penalty, &extra);
+        }
+        distribution = chain(0, &extra);
+    }
+    if die.success.is_some() && die.explode.map(|e| e.kind) == Some(ExplodeKind::Compound) {
+        let mut scores = BTreeMap::new();
+        for (value, p) in distribution {
+            *scores.entry(die.score(value)).or_insert(0.0) += p;
         }
+        distribution = scores;
     }
-    Ok(stack.pop().unwrap())
+    distribution.into_iter().collect()
 }
-fn roll_die(die: Die) -> i32 {
-    let mut rng = rand::thread_rng();
-    let mut current: i32 = 0;
-    for _n in 0..die.number {
-        current += rng.gen_range(1..die.sides + 1) as i32;
+/// The expected total of a die expression, taking all of its modifiers into account.
+fn average_die(die: &Die, explosion_limit: u32) -> f64 {
+    if die.is_simple() {
+        return die.number as f64 * die.mean_face();
+    }
+    let compound = die.explode.map(|e| e.kind) == Some(ExplodeKind::Compound);
+    if die.keep.is_none() && !(die.success.is_some() && compound) {
+        return die.number as f64 * mean_chain(die, explosion_limit);
+    }
+    let distribution = die_distribution(die, explosion_limit);
+    if die.keep.is_none() {
+        let mean: f64 = distribution.iter().map(|&(face, p)| face as f64 * p).sum();
+        return die.number as f64 * mean;
+    }
+    let (start, end) = die.kept_range(die.number);
+    expected_kept_sum(&distribution, die.number, start, end)
+}
+/// The expected value of a single die along with everything it explodes into.
+///
+/// Each explosion is reached with the chance `q` of a roll exploding, so the extra dice add up to a
+/// geometric series cut off at the explosion limit rather than having to be followed one by one.
+fn mean_chain(die: &Die, explosion_limit: u32) -> f64 {
+    let runs = face_runs(die);
+    // the faces of a run are evenly spaced, so their values add up to the number of them times the middle one
+    let mean = |penalty: i64| -> f64 {
+        let total = |run: &Run| {
+            let middle =
+                (roll_value(die, run.first - penalty) + roll_value(die, run.last - penalty)) as f64;
+            run.len() as f64 * middle / 2.0
+        };
+        runs.iter().map(|run| total(run) * run.chance).sum()
+    };
+    if die.explode.is_none() || explosion_limit == 0 {
+        return mean(0);
+    }
+    let q = explode_chance(die, &runs);
+    let n = explosion_limit as i32;
+    // q + q^2 + ... + q^n, the expected number of extra dice
+    let extra_dice = match q >= 1.0 {
+        true => n as f64,
+        false => q * (1.0 - q.powi(n)) / (1.0 - q),
+    };
+    mean(0) + extra_dice * mean(explosion_penalty(die))
+}
+/// Expected sum of the dice ranked `start..end` (lowest first) out of `number` independent rolls.
+///
+/// For each face `x` the count of dice showing at most `x` is binomially distributed, and the
+/// `j`th lowest die is at most `x` exactly when more than `j` dice are at most `x`.
+fn expected_kept_sum(distribution: &[(i64, f64)], number: u32, start: u32, end: u32) -> f64 {
+    let n = number as usize;
+    let mut ln_factorial = vec![0.0; n + 1];
+    for i in 1..=n {
+        ln_factorial[i] = ln_factorial[i - 1] + (i as f64).ln();
     }
-    current
+    let mut previous = vec![0.0; (end - start) as usize];
+    let mut cumulative = 0.0;
+    let mut expected = 0.0;
+    for &(face, p) in distribution {
+        cumulative += p;
+        let pmf = binomial_pmf(n, cumulative.min(1.0), &ln_factorial);
+        let mut tail = vec![0.0; n + 2];
+        for i in (0..=n).rev() {
+            tail[i] = tail[i + 1] + pmf[i];
+        }
+        for (slot, j) in (start as usize..end as usize).enumerate() {
+            let at_most = tail[j + 1];
+            expected += face as f64 * (at_most - previous[slot]);
+            previous[slot] = at_most;
+        }
+    }
+    expected
+}
+fn binomial_pmf(n: usize, p: f64, ln_factorial: &[f64]) -> Vec<f64> {
+    let mut pmf = vec![0.0; n + 1];
+    if p <= 0.0 {
+        pmf[0] = 1.0;
+    } else if p >= 1.0 {
+        pmf[n] = 1.0;
+    } else {
+        for (i, chance) in pmf.iter_mut().enumerate() {
+            let ln = ln_factorial[n] - ln_factorial[i] - ln_factorial[n - i]
+                + i as f64 * p.ln()
+                + (n - i) as f64 * (1.0 - p).ln();
+            *chance = ln.exp();
+        }
+    }
+    pmf
 }
 /// Rolls the given dice equation.
 ///
@@ -87,6 +1247,9 @@ fn roll_die(die: Die) -> i32 {
 /// sub-expressions together. For example, "10+(3+2d6*2)+3(2d20)+d2" is a valid equation that includes
 /// dice notation.
 ///
+/// The functions in this module are meant for equations typed by users and so stay within
+/// [`Limits::untrusted`], use [`Equation::new`] for equations that need more dice than that.
+///
 /// # Examples
 ///
 /// Rolling 1d4:
@@ -112,10 +1275,44 @@ fn roll_die(die: Die) -> i32 {
 /// let result = roll::roll("10+(3+2d6*2)+3(2d20)+d2").unwrap();
 /// println!("Result: {}", result);
 /// ```
+///
+/// Telling apart why an equation couldn't be rolled:
+/// ```
+/// use dice_forge::{roll, EvalError, InvalidExpressionError};
+///
+/// match roll::roll("1d6/(1d2-1)") {
+///     Err(InvalidExpressionError::Evaluate(EvalError::DivideByZero(_))) => println!("can't divide by 0"),
+///     Err(err) => println!("{}", err),
+///     Ok(result) => println!("Result: {}", result),
+/// }
+/// ```
 pub fn roll(input: &str) -> Result<i32, InvalidExpressionError> {
-    let compiled_equation = equation::infix_to_postfix(input)?;
-    let a = Equation { compiled_equation };
-    a.roll()
+    Equation::new_with_limits(input, Limits::untrusted())?.roll()
+}
+/// Rolls the given dice equation returning every result of a repeat group.
+///
+/// "Nx(...)" or "N#(...)" rolls the expression in parentheses N times, which is useful for rolling all of a
+/// character's stats or every attack in a turn at once. Equations without a repeat group return a single result.
+///
+/// # Examples
+///
+/// Rolling stats with 4d6 drop lowest:
+/// ```
+/// use dice_forge::roll;
+///
+/// let stats = roll::roll_many("6x(4d6kh3)").unwrap();
+/// println!("Stats: {:?}", stats);
+/// ```
+///
+/// Rolling three attacks:
+/// ```
+/// use dice_forge::roll;
+///
+/// let attacks = roll::roll_many("3#(1d20+5)").unwrap();
+/// println!("Attacks: {:?}", attacks);
+/// ```
+pub fn roll_many(input: &str) -> Result<Vec<i32>, InvalidExpressionError> {
+    Equation::new_with_limits(input, Limits::untrusted())?.roll_many()
 }
 /// Rolls the given dice equation with advantage.
 ///
@@ -156,9 +1353,7 @@ pub fn roll(input: &str) -> Result<i32, InvalidExpressionError> {
 /// println!("Result: {}", result);
 /// ```
 pub fn advantage(input: &str) -> Result<i32, InvalidExpressionError> {
-    let compiled_equation = equation::infix_to_postfix(input)?;
-    let a = Equation { compiled_equation };
-    a.advantage()
+    Equation::new_with_limits(input, Limits::untrusted())?.advantage()
 }
 /// Rolls the given dice equation with disadvantage.
 ///
@@ -199,9 +1394,7 @@ pub fn advantage(input: &str) -> Result<i32, InvalidExpressionError> {
 /// println!("Result: {}", result);
 /// ```
 pub fn disadvantage(input: &str) -> Result<i32, InvalidExpressionError> {
-    let compiled_equation = equation::infix_to_postfix(input)?;
-    let a = Equation { compiled_equation };
-    a.disadvantage()
+    Equation::new_with_limits(input, Limits::untrusted())?.disadvantage()
 }
 /// Rolls the given dice equation with emphasis.
 ///
@@ -234,7 +1427,5 @@ pub fn disadvantage(input: &str) -> Result<i32, InvalidExpressionError> {
 /// println!("Result: {}", result);
 /// ```
 pub fn emphasis(input: &str) -> Result<i32, InvalidExpressionError> {
-    let compiled_equation = equation::infix_to_postfix(input)?;
-    let a = Equation { compiled_equation };
-    a.emphasis()
+    Equation::new_with_limits(input, Limits::untrusted())?.emphasis()
 }
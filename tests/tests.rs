@@ -1,14 +1,18 @@
 #[cfg(test)]
 mod tests {
+    use dice_forge::ast::{fold_children, BinaryOp, Expr, Fold, Sides, Visitor};
+    use dice_forge::equation::{Dialect, Limits, RoundingPolicy};
     use dice_forge::roll;
     use dice_forge::Equation;
+    use dice_forge::{EvalError, InvalidExpressionError, ParseError};
+    use std::collections::HashMap;
     #[test]
     //#[should_panic(expected = "Divide by zero error")]
     fn devide_zero() {
-        match Equation::new("5/0").expect("test").roll() {
-            Ok(_v) => {}
+        match Equation::new("5/0") {
+            Ok(_v) => panic!(),
             Err(e) => {
-                assert_eq!("Error: Attempted to divide by 0", format!("{}", e));
+                assert_eq!("Error: Attempted to divide by 0\n5/0\n ^", format!("{}", e));
             }
         }
     }
@@ -109,7 +113,7 @@ mod tests {
     fn roll_call_err_0() {
         match roll::roll("1/0") {
             Ok(_v) => {}
-            Err(e) => assert_eq!("Error: Attempted to divide by 0", format!("{}", e)),
+            Err(e) => assert_eq!("Error: Attempted to divide by 0\n1/0\n ^", format!("{}", e)),
         }
     }
     #[test]
@@ -117,7 +121,7 @@ mod tests {
         match roll::roll("test") {
             Ok(_v) => {}
             Err(e) => assert_eq!(
-                "Error: Unexpected token \'t\' found while parsing",
+                "Error: Unexpected token \'t\' found while parsing\ntest\n^~~~",
                 format!("{}", e)
             ),
         }
@@ -137,4 +141,1241 @@ mod tests {
         let my_eq = Equation::new("d20").unwrap();
         let _roll = my_eq.emphasis().unwrap();
     }
+    #[test]
+    fn keep_highest() {
+        let q = Equation::new("4d6kh3").unwrap();
+        assert_eq!((3, 18), q.range().unwrap());
+        assert_eq!(12, q.average().unwrap());
+        for _n in 0..100 {
+            let r = q.roll().unwrap();
+            assert!((3..=18).contains(&r));
+        }
+    }
+    #[test]
+    fn keep_lowest_and_drop() {
+        assert_eq!((1, 20), Equation::new("2d20kl1").unwrap().range().unwrap());
+        assert_eq!(7, Equation::new("2d20kl1").unwrap().average().unwrap());
+        assert_eq!((3, 18), Equation::new("4d6dl1").unwrap().range().unwrap());
+        assert_eq!((3, 18), Equation::new("4d6dh").unwrap().range().unwrap());
+        assert_eq!(3, Equation::new("5d1d2").unwrap().roll().unwrap());
+        assert_eq!(18, Equation::new("2d20kh1+5").unwrap().average().unwrap());
+        // a second keep or drop modifier is an error rather than replacing the first
+        for (input, span) in [
+            ("4d6kh3kl1", 6..9),
+            ("4d6dl1k2", 6..8),
+            ("4d6 kh3 dh 1", 8..12),
+        ] {
+            match Equation::new(input) {
+                Err(InvalidExpressionError::Parse(ParseError::InvalidDie(location))) => {
+                    assert_eq!(span, location.span, "{}", input)
+                }
+                _ => panic!("{}", input),
+            }
+        }
+    }
+    #[test]
+    fn exploding_dice() {
+        let q = Equation::new("1d6!").unwrap();
+        assert_eq!((1, 606), q.range().unwrap());
+        assert_eq!(4, q.average().unwrap());
+        assert_eq!(18, q.with_explosion_limit(2).high().unwrap());
+        assert_eq!(101, Equation::new("d1!").unwrap().roll().unwrap());
+        assert_eq!(
+            4,
+            Equation::new("d1!!")
+                .unwrap()
+                .with_explosion_limit(3)
+                .roll()
+                .unwrap()
+        );
+        assert_eq!(
+            1,
+            Equation::new("d1!p")
+                .unwrap()
+                .with_explosion_limit(3)
+                .roll()
+                .unwrap()
+        );
+        assert_eq!((1, 1010), Equation::new("d10!>8").unwrap().range().unwrap());
+        // a die that nearly always explodes has a very long chain to follow
+        let q = Equation::new("1d1000!>1").unwrap();
+        assert_eq!((1, 101000), q.range().unwrap());
+        assert_eq!(48104, q.average().unwrap());
+        assert_eq!(
+            (-303, 1),
+            Equation::new("1d{-3,0,1}!<0").unwrap().range().unwrap()
+        );
+    }
+    #[test]
+    fn exploding_compare_points() {
+        assert_eq!(
+            30,
+            Equation::new("d10!>=8")
+                .unwrap()
+                .with_explosion_limit(2)
+                .high()
+                .unwrap()
+        );
+        assert_eq!(
+            16,
+            Equation::new("d6!p")
+                .unwrap()
+                .with_explosion_limit(2)
+                .high()
+                .unwrap()
+        );
+        assert_eq!(
+            7,
+            Equation::new("d6!1")
+                .unwrap()
+                .with_explosion_limit(1)
+                .high()
+                .unwrap()
+        );
+        assert!(Equation::new("d6!>").is_err());
+    }
+    #[test]
+    fn reroll_dice() {
+        let q = Equation::new("2d6r<=2").unwrap();
+        assert_eq!((6, 12), q.range().unwrap());
+        assert_eq!(9, q.average().unwrap());
+        for _n in 0..100 {
+            assert!(q.roll().unwrap() >= 6);
+        }
+        assert_eq!((2, 12), Equation::new("2d6ro<3").unwrap().range().unwrap());
+        assert_eq!(8, Equation::new("2d6ro<3").unwrap().average().unwrap());
+        assert_eq!(2, Equation::new("1d2r1").unwrap().roll().unwrap());
+        // the sides of these dice aren't known until they are rolled
+        assert_eq!((2, 12), Equation::new("2d(6)r<1").unwrap().range().unwrap());
+        assert_eq!((2, 12), Equation::new("2d(6)r0").unwrap().range().unwrap());
+        assert_eq!((4, 12), Equation::new("2d(6)r<2").unwrap().range().unwrap());
+    }
+    #[test]
+    fn reroll_limit() {
+        match Equation::new("d1r1") {
+            Ok(_) => panic!(),
+            Err(e) => assert_eq!(
+                "Error: Die could not stop rerolling within the reroll limit\nd1r1\n^~~~",
+                format!("{}", e)
+            ),
+        }
+        assert_eq!(1, Equation::new("d1ro1").unwrap().roll().unwrap());
+        assert!(Equation::new("d6r").is_err());
+        // whether every face rerolls is worked out without listing the faces
+        assert!(Equation::new("1d2000000000r1").is_ok());
+        assert!(Equation::new("1d2000000000r>1").is_ok());
+        assert!(matches!(
+            Equation::new("1d2000000000r<2000000001"),
+            Err(InvalidExpressionError::Parse(ParseError::RerollLimit(_)))
+        ));
+        assert!(matches!(
+            Equation::new("dFr<2"),
+            Err(InvalidExpressionError::Parse(ParseError::RerollLimit(_)))
+        ));
+        assert!(Equation::new("d{1,3,5}r<5").is_ok());
+        assert!(matches!(
+            roll::roll("1d2000000000r1"),
+            Err(InvalidExpressionError::Parse(ParseError::TooManySides(_)))
+        ));
+    }
+    #[test]
+    fn success_pool() {
+        let q = Equation::new("10d10>=8f1").unwrap();
+        assert_eq!((-10, 10), q.range().unwrap());
+        assert_eq!(2, q.average().unwrap());
+        for _n in 0..100 {
+            assert!((-10..=10).contains(&q.roll().unwrap()));
+        }
+        assert_eq!((0, 3), Equation::new("3d6=6").unwrap().range().unwrap());
+        assert_eq!(5, Equation::new("5d1<=1").unwrap().roll().unwrap());
+        assert_eq!(3, Equation::new("5d2>1kl3").unwrap().high().unwrap());
+    }
+    #[test]
+    fn success_pool_explode() {
+        let q = Equation::new("2d10>=8!").unwrap().with_explosion_limit(2);
+        assert_eq!((0, 6), q.range().unwrap());
+        assert!(Equation::new("4d6f1").is_err());
+    }
+    #[test]
+    fn fudge_dice() {
+        let q = Equation::new("4dF").unwrap();
+        assert_eq!((-4, 4), q.range().unwrap());
+        assert_eq!(0, q.average().unwrap());
+        for _n in 0..100 {
+            assert!((-4..=4).contains(&q.roll().unwrap()));
+        }
+        assert_eq!((1, 3), Equation::new("4dFkh1+2").unwrap().range().unwrap());
+        // compare points may be negative to match the blank and minus faces
+        assert_eq!((0, 4), Equation::new("4dF>=0").unwrap().range().unwrap());
+        assert_eq!((4, 4), Equation::new("4dF>=-1").unwrap().range().unwrap());
+        assert_eq!((0, 4), Equation::new("4dFr=-1").unwrap().range().unwrap());
+        assert_eq!(
+            (-4, 4),
+            Equation::new("4dF=-1f>-1").unwrap().range().unwrap()
+        );
+        assert_eq!(-5, Equation::new("4dF!-1").unwrap().range().unwrap().0);
+    }
+    #[test]
+    fn percentile_dice() {
+        let q = Equation::new("d%").unwrap();
+        assert_eq!((1, 100), q.range().unwrap());
+        assert_eq!(50, q.average().unwrap());
+        assert_eq!((2, 200), Equation::new("2d%").unwrap().range().unwrap());
+    }
+    #[test]
+    fn custom_dice() {
+        let q = Equation::new("2d{1,1,2,3,5,8}").unwrap();
+        assert_eq!((2, 16), q.range().unwrap());
+        assert_eq!(6, q.average().unwrap());
+        for _n in 0..100 {
+            assert!([2, 3, 4, 5, 6, 7, 8, 9, 10, 11, 13, 16].contains(&q.roll().unwrap()));
+        }
+        assert_eq!(
+            (-1, 1),
+            Equation::new("d{-1,0,0,1}").unwrap().range().unwrap()
+        );
+        assert_eq!((0, 3), Equation::new("3d{0,0,1}").unwrap().range().unwrap());
+        let q = Equation::new("d{-1,0,1}r=-1").unwrap();
+        assert_eq!((0, 1), q.range().unwrap());
+        for _n in 0..100 {
+            assert!((0..=1).contains(&q.roll().unwrap()));
+        }
+        assert!(matches!(
+            Equation::new("d{-1,0,1}r>=-1"),
+            Err(InvalidExpressionError::Parse(ParseError::RerollLimit(_)))
+        ));
+        assert!(Equation::new("d{-1,0,1}r=-").is_err());
+        assert_eq!(1, Equation::new("3d{0,0,1}").unwrap().average().unwrap());
+    }
+    #[test]
+    fn custom_dice_invalid() {
+        assert!(Equation::new("d{}").is_err());
+        assert!(Equation::new("d{1,2").is_err());
+        assert!(Equation::new("d{1,,2}").is_err());
+    }
+    #[test]
+    fn variables() {
+        let q = Equation::new("1d20 + @str_mod + @prof * 2 - @str_mod").unwrap();
+        assert_eq!(vec!["str_mod", "prof"], q.variables());
+        let vars = HashMap::from([("str_mod", 3), ("prof", 2)]);
+        assert_eq!((5, 24), q.range_with_vars(&vars).unwrap());
+        assert_eq!(14, q.average_with_vars(&vars).unwrap());
+        let r = q.roll_with_vars(&vars).unwrap();
+        assert!((5..=24).contains(&r));
+        assert_eq!(
+            6,
+            Equation::new("2@x")
+                .unwrap()
+                .roll_with_vars(&HashMap::from([("x", 3)]))
+                .unwrap()
+        );
+        // a name ends at whitespace, the d6 after it is rolled @x times
+        let q = Equation::new("@x d6").unwrap();
+        assert_eq!(vec!["x"], q.variables());
+        assert_eq!(
+            (2, 12),
+            q.range_with_vars(&HashMap::from([("x", 2)])).unwrap()
+        );
+        assert_eq!(
+            vec!["x", "y"],
+            Equation::new("@x + @y").unwrap().variables()
+        );
+        assert_eq!(vec!["xd6"], Equation::new("@xd6").unwrap().variables());
+        assert!(Equation::new("@ x").is_err());
+    }
+    #[test]
+    fn unbound_variable() {
+        match Equation::new("1d20 + @prof").unwrap().roll() {
+            Ok(_) => panic!(),
+            Err(e) => assert_eq!(
+                "Error: No value was given for the variable \'@prof\'\n1d20 + @prof\n       ^~~~~",
+                format!("{}", e)
+            ),
+        }
+        assert!(Equation::new("1d20 + @").is_err());
+    }
+    #[test]
+    fn functions() {
+        assert_eq!(5, roll::roll("max(1, 5, 3)").unwrap());
+        assert_eq!(1, roll::roll("min(4, 1, 3)").unwrap());
+        assert_eq!(7, roll::roll("abs(2 - 9)").unwrap());
+        assert_eq!(10, roll::roll("clamp(12, 3, 10)").unwrap());
+        assert_eq!(3, roll::roll("clamp(1d1, 3, 10)").unwrap());
+        assert_eq!(4, roll::roll("max(1, 2)*2").unwrap());
+        assert_eq!(1, roll::roll("max(1, 2)-1").unwrap());
+        assert_eq!(6, roll::roll("2max(1, 3)").unwrap());
+        let q = Equation::new("max(1, 1d6-2)").unwrap();
+        assert_eq!((1, 4), q.range().unwrap());
+    }
+    #[test]
+    fn rounding_functions() {
+        assert_eq!(-4, roll::roll("floor((1-8)/2)").unwrap());
+        assert_eq!(3, roll::roll("floor(7/2)").unwrap());
+        assert_eq!(4, roll::roll("ceil(7/2)").unwrap());
+        assert_eq!(-3, roll::roll("ceil(7/(0-2))").unwrap());
+        assert_eq!(4, roll::roll("round(7/2)").unwrap());
+        assert_eq!(1, roll::roll("round(4/3)").unwrap());
+        assert_eq!(5, roll::roll("floor(5)").unwrap());
+        // the whole argument is rounded once rather than each division in it
+        assert_eq!(5, roll::roll("ceil(7/2+1)").unwrap());
+        assert_eq!(7, roll::roll("ceil(7/2*2)").unwrap());
+        assert_eq!(8, roll::roll("round(5/2*3)").unwrap());
+        assert_eq!(-4, roll::roll("floor((0-7)/2+0)").unwrap());
+        assert_eq!(3, roll::roll("2*floor(3/2)+1").unwrap());
+        assert_eq!(4, Equation::new("round(1d6)").unwrap().average().unwrap());
+        assert!(roll::roll("clamp(1, 2)").is_err());
+        assert!(roll::roll("1, 2").is_err());
+        assert!(roll::roll("sqrt(4)").is_err());
+    }
+    #[test]
+    fn comparisons() {
+        assert_eq!(1, roll::roll("3 + 2 >= 5").unwrap());
+        assert_eq!(0, roll::roll("3 + 2 > 5").unwrap());
+        assert_eq!(1, roll::roll("4 == 2 * 2").unwrap());
+        assert_eq!(1, roll::roll("4 != 5").unwrap());
+        assert_eq!(1, roll::roll("1 < 2 && 2 <= 2").unwrap());
+        assert_eq!(0, roll::roll("1 > 2 || 3 < 2").unwrap());
+        assert_eq!(1, roll::roll("0 || 7").unwrap());
+        assert_eq!(0, roll::roll("0 && 7").unwrap());
+        assert!(roll::roll("1 = 1").is_err());
+        assert!(roll::roll("1 & 1").is_err());
+        // a comparison straight after a die compares its total rather than changing the die
+        let q = Equation::new("1d6 != 3").unwrap();
+        for _n in 0..100 {
+            assert!((0..=1).contains(&q.roll().unwrap()));
+        }
+        assert_eq!(1, roll::roll("1d1 != 3").unwrap());
+        assert_eq!(1, roll::roll("1d1!=3").unwrap());
+        assert_eq!(
+            (0, 1),
+            Equation::new("1d20 == 20").unwrap().range().unwrap()
+        );
+        assert_eq!(1, roll::roll("1d1==1").unwrap());
+        assert_eq!((0, 1), Equation::new("2d6 > 7").unwrap().range().unwrap());
+        assert_eq!(1, roll::roll("4d1kh3 < 10").unwrap());
+        assert_eq!(0, roll::roll("1d1! >= 200").unwrap());
+        // without the space it is still a success pool
+        assert_eq!((0, 2), Equation::new("2d6>4").unwrap().range().unwrap());
+        // a comparison of dice can go either way, whatever the extremes of its sides give
+        assert_eq!((0, 1), Equation::new("1d6 != 3").unwrap().range().unwrap());
+        assert_eq!(
+            (0, 2),
+            Equation::new("(1d6 != 3) + (2d6 == 7)")
+                .unwrap()
+                .range()
+                .unwrap()
+        );
+    }
+    #[test]
+    fn conditionals() {
+        assert_eq!(7, roll::roll("1 ? 7 : 9").unwrap());
+        assert_eq!(9, roll::roll("0 ? 7 : 9").unwrap());
+        assert_eq!(3, roll::roll("0 ? 1 : 0 ? 2 : 3").unwrap());
+        assert_eq!(2, roll::roll("1 ? 0 ? 1 : 2 : 3").unwrap());
+        assert_eq!(11, roll::roll("(2 > 1 ? 5 : 6) + 6").unwrap());
+        assert_eq!(6, roll::roll("max(1 ? 6 : 2, 3)").unwrap());
+        let attack = Equation::new("1d20+5 >= 15 ? 2d6+3 : 0").unwrap();
+        assert_eq!((0, 15), attack.range().unwrap());
+        for _n in 0..100 {
+            let r = attack.roll().unwrap();
+            assert!(r == 0 || (5..=15).contains(&r));
+        }
+        // the range covers both branches, however the dice that decide between them fall
+        let q = Equation::new("1d20 >= 15 ? 0 : 2d6").unwrap();
+        assert_eq!((0, 12), q.range().unwrap());
+        assert_eq!((0, 12), q.range_i64().unwrap());
+        assert_eq!((0.0, 12.0), q.range_f64().unwrap());
+        assert_eq!(
+            (1, 6),
+            Equation::new("1d2 == 1 ? 6 : 1").unwrap().range().unwrap()
+        );
+        // a constant condition only ever takes the one branch
+        assert_eq!(
+            (2, 12),
+            Equation::new("1 ? 2d6 : 9").unwrap().range().unwrap()
+        );
+        assert!(roll::roll("1 ? 2").is_err());
+        assert!(roll::roll("1 : 2").is_err());
+    }
+    #[test]
+    fn short_circuit() {
+        assert_eq!(0, roll::roll("0 ? 1/0 : 0").unwrap());
+        assert_eq!(0, roll::roll("0 && 1/0").unwrap());
+        assert_eq!(1, roll::roll("1 || 1/0").unwrap());
+        assert_eq!(0, roll::roll("0 ? @unbound : 0").unwrap());
+    }
+    #[test]
+    fn dynamic_dice() {
+        let q = Equation::new("(1d4)d6").unwrap();
+        assert_eq!((1, 24), q.range().unwrap());
+        for _n in 0..100 {
+            assert!((1..=24).contains(&q.roll().unwrap()));
+        }
+        let q = Equation::new("2d(1d3*4)").unwrap();
+        assert_eq!((2, 24), q.range().unwrap());
+        assert_eq!(3, roll::roll("(1+2)d1").unwrap());
+        assert_eq!(2, roll::roll("2d(1)").unwrap());
+        assert_eq!(8, Equation::new("2d(2*2)").unwrap().high().unwrap());
+        assert_eq!(
+            (1, 3),
+            Equation::new("(2+2)d(3)kh1").unwrap().range().unwrap()
+        );
+        // the averages of the number and sides keep their fractions
+        let q_average = |input: &str| Equation::new(input).unwrap().average_f64().unwrap();
+        assert_eq!(8.75, q_average("(1d4)d6"));
+        assert_eq!(7.875, q_average("(1d6)d(1d6)"));
+        assert_eq!(10.5, q_average("3d(2d6-1)"));
+        // whole numbers cut the fractions off of the average of the 1d4 before it is used
+        assert_eq!(7, Equation::new("(1d4)d6").unwrap().average_i64().unwrap());
+    }
+    #[test]
+    fn dynamic_dice_variables() {
+        let vars = HashMap::from([("level", 3), ("sides", 8)]);
+        let q = Equation::new("(@level)d6").unwrap();
+        assert_eq!((3, 18), q.range_with_vars(&vars).unwrap());
+        let q = Equation::new("2d@sides + 1").unwrap();
+        assert_eq!((3, 17), q.range_with_vars(&vars).unwrap());
+        let vars = HashMap::from([("level", 0)]);
+        assert!(Equation::new("(@level)d6")
+            .unwrap()
+            .roll_with_vars(&vars)
+            .is_err());
+        assert!(roll::roll("(0-1)d6").is_err());
+        assert!(roll::roll("2d(0)").is_err());
+    }
+    #[test]
+    fn repeat_groups() {
+        let stats = Equation::new("6x(4d6kh3)").unwrap().roll_many().unwrap();
+        assert_eq!(6, stats.len());
+        assert!(stats.iter().all(|s| (3..=18).contains(s)));
+        assert_eq!(vec![6, 6, 6], roll::roll_many("3#(1d1+5)").unwrap());
+        assert_eq!(vec![7], roll::roll_many("3+4").unwrap());
+        assert_eq!(20, roll::roll("sum(4x(1d1+4))").unwrap());
+        assert_eq!(22, roll::roll("sum(4x(5)) + 2").unwrap());
+        assert_eq!(5, roll::roll("max(3x(5), 2)").unwrap());
+        assert_eq!(vec![2, 2], roll::roll_many("(1+1)x(2)").unwrap());
+        assert!(roll::roll_many("0x(2)").unwrap().is_empty());
+    }
+    #[test]
+    fn repeat_group_as_number() {
+        match roll::roll("6x(1d6) + 2") {
+            Ok(_) => panic!(),
+            Err(e) => assert_eq!(
+                "Error: A repeated roll gives a list of results and can only be used inside sum(), min() or max()\n6x(1d6) + 2\n        ^",
+                format!("{}", e)
+            ),
+        }
+        assert!(roll::roll("6x(1d6)").is_err());
+        assert!(roll::roll("abs(2x(1))").is_err());
+        assert!(roll::roll("x(1d6)").is_err());
+        assert!(roll::roll("2x1").is_err());
+    }
+    #[test]
+    fn labels() {
+        let damage = Equation::new("1d1[slashing] + 2d1[fire] + 3 + 1[fire]")
+            .unwrap()
+            .roll_labeled()
+            .unwrap();
+        assert_eq!(7, damage.total);
+        assert_eq!(
+            vec![("slashing".to_string(), 1), ("fire".to_string(), 3)],
+            damage.labels
+        );
+        let damage = roll::roll("(2 + 3)[cold iron] * 2").unwrap();
+        assert_eq!(10, damage);
+        let damage = Equation::new("(2 + 3)[cold iron] * 2")
+            .unwrap()
+            .roll_labeled()
+            .unwrap();
+        assert_eq!(vec![("cold iron".to_string(), 5)], damage.labels);
+        let damage = Equation::new("0 ? 1[fire] : 2[cold]")
+            .unwrap()
+            .roll_labeled()
+            .unwrap();
+        assert_eq!(vec![("cold".to_string(), 2)], damage.labels);
+    }
+    #[test]
+    fn labels_invalid() {
+        assert!(roll::roll("[fire]").is_err());
+        assert!(roll::roll("1d6[fire").is_err());
+        assert!(roll::roll("1d6 + [fire]").is_err());
+    }
+    #[test]
+    fn f64_mode() {
+        assert_eq!(3.5, Equation::new("7/2").unwrap().roll_f64().unwrap());
+        assert_eq!(3, Equation::new("7/2").unwrap().roll().unwrap());
+        assert_eq!(3.5, Equation::new("1d6").unwrap().average_f64().unwrap());
+        assert_eq!(10.5, Equation::new("3d6").unwrap().average_f64().unwrap());
+        assert_eq!(
+            (0.5, 3.0),
+            Equation::new("1d6/2").unwrap().range_f64().unwrap()
+        );
+        assert_eq!(0.25, Equation::new("2^(0-2)").unwrap().roll_f64().unwrap());
+        assert_eq!(
+            1.5,
+            Equation::new("max(1/2, 3/2)").unwrap().roll_f64().unwrap()
+        );
+        assert_eq!(1.0, Equation::new("7/4 > 3/2").unwrap().roll_f64().unwrap());
+        assert!(Equation::new("1/(1d1-1)").unwrap().roll_f64().is_err());
+        let half = Equation::new("1d5/2").unwrap();
+        assert_eq!(0.5, half.low_f64().unwrap());
+        assert_eq!(2.5, half.high_f64().unwrap());
+        let vars = HashMap::from([("level", 3)]);
+        let heal = Equation::new("1d5/2 + @level/2").unwrap();
+        assert_eq!((2.0, 4.0), heal.range_f64_with_vars(&vars).unwrap());
+        assert_eq!(2.0, heal.low_f64_with_vars(&vars).unwrap());
+        assert_eq!(4.0, heal.high_f64_with_vars(&vars).unwrap());
+    }
+    #[test]
+    fn f64_mode_rounding() {
+        assert_eq!(
+            3.0,
+            Equation::new("floor(7/2)").unwrap().roll_f64().unwrap()
+        );
+        assert_eq!(
+            4.0,
+            Equation::new("round(1d6)").unwrap().average_f64().unwrap()
+        );
+        assert_eq!(2.0, Equation::new("ceil(3/2)").unwrap().roll_f64().unwrap());
+        // dice counts use the whole part of the expression
+        assert_eq!(3.0, Equation::new("(7/2)d1").unwrap().roll_f64().unwrap());
+    }
+    #[test]
+    fn rounding_policy() {
+        let divide = |input: &str, rounding| {
+            Equation::new(input)
+                .unwrap()
+                .with_rounding(rounding)
+                .roll()
+                .unwrap()
+        };
+        assert_eq!(-3, divide("(1-8)/2", RoundingPolicy::Truncate));
+        assert_eq!(-4, divide("(1-8)/2", RoundingPolicy::Floor));
+        assert_eq!(4, divide("7/2", RoundingPolicy::Ceil));
+        assert_eq!(-3, divide("(1-8)/2", RoundingPolicy::Ceil));
+        assert_eq!(4, divide("7/2", RoundingPolicy::HalfUp));
+        assert_eq!(2, divide("7/3", RoundingPolicy::HalfUp));
+        assert_eq!(-4, divide("(1-8)/2", RoundingPolicy::HalfUp));
+        // the rounding functions ignore the policy
+        assert_eq!(3, divide("floor(7/2)", RoundingPolicy::Ceil));
+        assert_eq!(
+            3.5,
+            Equation::new("7/2")
+                .unwrap()
+                .with_rounding(RoundingPolicy::Floor)
+                .roll_f64()
+                .unwrap()
+        );
+    }
+    #[test]
+    fn rounding_policy_stats() {
+        let equation = Equation::new("1d6/4").unwrap();
+        assert_eq!((0, 1), equation.range().unwrap());
+        let equation = equation.with_rounding(RoundingPolicy::Ceil);
+        assert_eq!((1, 2), equation.range().unwrap());
+        let equation = Equation::new("1d6").unwrap();
+        assert_eq!(3, equation.average().unwrap());
+        let equation = equation.with_rounding(RoundingPolicy::HalfUp);
+        assert_eq!(4, equation.average().unwrap());
+        let equation = Equation::new("2d6/3")
+            .unwrap()
+            .with_rounding(RoundingPolicy::HalfUp);
+        assert_eq!(2, equation.average().unwrap());
+    }
+    #[test]
+    fn exponents() {
+        assert_eq!(512, roll::roll("2^3^2").unwrap());
+        assert_eq!(64, roll::roll("(2^3)^2").unwrap());
+        assert_eq!(17, roll::roll("(1+2^4)").unwrap());
+        assert_eq!(18, roll::roll("2*3^2").unwrap());
+        assert_eq!(1, roll::roll("5^0").unwrap());
+        assert_eq!(0.125, Equation::new("2^(0-3)").unwrap().roll_f64().unwrap());
+        assert_eq!(3.0, Equation::new("9^(1/2)").unwrap().roll_f64().unwrap());
+        // a minus with nothing before it only negates the value after it
+        assert_eq!(0.5, Equation::new("2^-1").unwrap().roll_f64().unwrap());
+        assert_eq!(2.0, Equation::new("2^-1*4").unwrap().roll_f64().unwrap());
+        assert_eq!(-4, roll::roll("-2^2").unwrap());
+        assert_eq!(-6, roll::roll("2*-3").unwrap());
+        assert_eq!(3, roll::roll("1 - -2").unwrap());
+    }
+    #[test]
+    fn exponent_errors() {
+        match roll::roll("2^(0-2)") {
+            Err(e) => assert_eq!(
+                "Error: A whole number can't be raised to a negative exponent, use roll_f64() for fractions\n2^(0-2)\n ^",
+                e.to_string()
+            ),
+            Ok(_) => panic!("negative exponent should error"),
+        }
+        assert!(matches!(
+            roll::roll("2^-1"),
+            Err(InvalidExpressionError::Evaluate(
+                EvalError::NegativeExponent(_)
+            ))
+        ));
+        match roll::roll("10^10") {
+            Err(e) => assert_eq!(
+                "Error: The result was too large to be stored\n10^10\n  ^",
+                e.to_string()
+            ),
+            Ok(_) => panic!("overflowing exponent should error"),
+        }
+    }
+    #[test]
+    fn malformed_expressions() {
+        for input in [
+            "+",
+            "1+",
+            "()",
+            "(1",
+            "1)",
+            "*3",
+            "(2)3",
+            "max(1,",
+            "1?2",
+            "2x(1+)",
+            "1d6[fire]3",
+            "1?:",
+            "1?2:",
+        ] {
+            match Equation::new(input) {
+                Err(e) => assert_eq!(
+                    "Error: The expression could not be parsed, incorrect format?",
+                    e.to_string().lines().next().unwrap(),
+                    "{}",
+                    input
+                ),
+                Ok(_) => panic!("{} should not compile", input),
+            }
+        }
+    }
+    #[test]
+    fn malformed_dice() {
+        for input in ["d", "1d0", "2d", "1d6+d"] {
+            match Equation::new(input) {
+                Err(e) => assert_eq!(
+                    "Error: Die expression could not be parsed.",
+                    e.to_string().lines().next().unwrap(),
+                    "{}",
+                    input
+                ),
+                Ok(_) => panic!("{} should not compile", input),
+            }
+        }
+        assert!(Equation::new("99999999999").is_err());
+        assert!(Equation::new("1d99999999999").is_err());
+        assert_eq!(2147483647, roll::roll("2147483647").unwrap());
+    }
+    #[test]
+    fn error_positions() {
+        let caret = |input: &str| match Equation::new(input) {
+            Err(e) => e.to_string().lines().skip(1).collect::<Vec<_>>().join("\n"),
+            Ok(_) => panic!("{} should not compile", input),
+        };
+        assert_eq!("1d6 + 3d0\n      ^~~", caret("1d6 + 3d0"));
+        assert_eq!("2 * (1 + 2\n    ^", caret("2 * (1 + 2"));
+        assert_eq!("1 + 2)\n     ^", caret("1 + 2)"));
+        assert_eq!("clamp(1, 2) + 3\n^~~~~~~~~~~", caret("clamp(1, 2) + 3"));
+        assert_eq!("2 ≠ 3\n  ^", caret("2 ≠ 3"));
+        assert_eq!("1 ? 2 + 3\n  ^", caret("1 ? 2 + 3"));
+        match Equation::new("1d20 + 10 / (1d1 - 1d1)").unwrap().roll() {
+            Err(e) => assert_eq!(10..11, e.location().span),
+            Ok(_) => panic!(),
+        }
+    }
+    #[test]
+    fn error_types() {
+        assert!(matches!(
+            Equation::new("1d0"),
+            Err(InvalidExpressionError::Parse(ParseError::InvalidDie(_)))
+        ));
+        assert!(matches!(
+            roll::roll("1d6/0"),
+            Err(InvalidExpressionError::Parse(ParseError::DivideByZero(_)))
+        ));
+        assert!(matches!(
+            Equation::new("1d6/(1d1-1)").unwrap().roll(),
+            Err(InvalidExpressionError::Evaluate(EvalError::DivideByZero(_)))
+        ));
+        assert!(matches!(
+            Equation::new("1d6+@str").unwrap().roll(),
+            Err(InvalidExpressionError::Evaluate(EvalError::UnboundVariable(name, _))) if name == "str"
+        ));
+    }
+    #[test]
+    fn error_trait() {
+        fn total(input: &str) -> Result<i32, Box<dyn std::error::Error>> {
+            Ok(Equation::new(input)?.roll()?)
+        }
+        assert_eq!(5, total("2+3").unwrap());
+        let err = total("2+").unwrap_err();
+        assert!(err.to_string().starts_with("Error: "));
+        assert!(err.downcast_ref::<InvalidExpressionError>().is_some());
+    }
+    #[test]
+    fn overflow() {
+        let overflows = |input: &str| {
+            matches!(
+                Equation::new(input).unwrap().roll(),
+                Err(InvalidExpressionError::Evaluate(EvalError::Overflow(_)))
+            )
+        };
+        assert!(overflows("1000d1000*1000d1000"));
+        assert!(overflows("9^20"));
+        assert!(overflows("2147483647+1"));
+        assert!(overflows("(0-2147483647-1)/(0-1)"));
+        assert!(overflows("abs(0-2147483647-1)"));
+        assert!(matches!(
+            Equation::new("3d2147483647").unwrap().high(),
+            Err(InvalidExpressionError::Evaluate(EvalError::Overflow(_)))
+        ));
+        assert!(matches!(
+            roll::roll("99999999999"),
+            Err(InvalidExpressionError::Parse(ParseError::Overflow(_)))
+        ));
+        assert_eq!(2147483647, roll::roll("2147483646+1").unwrap());
+        match Equation::new("1 + 2147483647 * 2").unwrap().roll() {
+            Err(e) => assert_eq!(15..16, e.location().span),
+            Ok(_) => panic!(),
+        }
+    }
+    #[test]
+    fn i64_mode() {
+        let big = Equation::new("1000d1000*1000d1000").unwrap();
+        let result = big.roll_i64().unwrap();
+        assert!((1_000_000..=1_000_000_000_000).contains(&result));
+        assert_eq!((1_000_000, 1_000_000_000_000), big.range_i64().unwrap());
+        assert_eq!(
+            4_052_555_153_018_976_267,
+            Equation::new("3^39").unwrap().roll_i64().unwrap()
+        );
+        let vars = HashMap::from([("gold", 2_000_000_000)]);
+        assert_eq!(
+            4_000_000_000,
+            Equation::new("@gold*2")
+                .unwrap()
+                .roll_i64_with_vars(&vars)
+                .unwrap()
+        );
+        let hoard = Equation::new("@gold*1d3").unwrap();
+        assert_eq!(4_000_000_000, hoard.average_i64_with_vars(&vars).unwrap());
+        assert_eq!(
+            (2_000_000_000, 6_000_000_000),
+            hoard.range_i64_with_vars(&vars).unwrap()
+        );
+        assert_eq!(2_000_000_000, hoard.low_i64_with_vars(&vars).unwrap());
+        assert_eq!(6_000_000_000, hoard.high_i64_with_vars(&vars).unwrap());
+        assert!(hoard.average_with_vars(&vars).is_err());
+        assert_eq!(
+            6_442_450_941,
+            Equation::new("3d2147483647")
+                .unwrap()
+                .range_i64()
+                .unwrap()
+                .1
+        );
+        assert!(Equation::new("3^40").unwrap().roll_i64().is_err());
+        assert_eq!(1_000_000, big.low_i64().unwrap());
+        assert_eq!(1_000_000_000_000, big.high_i64().unwrap());
+    }
+    #[test]
+    fn limits() {
+        let limits = Limits::untrusted();
+        let parse_error = |input: &str| match Equation::new_with_limits(input, limits) {
+            Err(InvalidExpressionError::Parse(err)) => err,
+            _ => panic!("{} should not compile", input),
+        };
+        assert!(matches!(
+            parse_error("999999999d999999999"),
+            ParseError::TooManySides(_)
+        ));
+        assert!(matches!(parse_error("1001d6"), ParseError::TooManyDice(_)));
+        assert!(matches!(
+            parse_error("600d6 + 600d6"),
+            ParseError::TooManyDice(_)
+        ));
+        assert!(matches!(parse_error("1d1001"), ParseError::TooManySides(_)));
+        // the sides are checked before the modifiers are worked out
+        assert!(matches!(
+            parse_error("1d2000r<3000"),
+            ParseError::TooManySides(_)
+        ));
+        assert_eq!(0..6, parse_error("1d2000r<3000").location().span);
+        assert!(matches!(
+            parse_error(&"1+".repeat(300)),
+            ParseError::TooLong(_)
+        ));
+        let deep = format!("{}1{}", "(".repeat(33), ")".repeat(33));
+        assert!(matches!(parse_error(&deep), ParseError::TooDeep(_)));
+        assert_eq!(32..33, parse_error(&deep).location().span);
+        assert!(Equation::new_with_limits("1000d1000", limits).is_ok());
+        assert!(Equation::new("999999d6+1d1001").is_ok());
+        let eval_error = |input: &str| match roll::roll(input) {
+            Err(InvalidExpressionError::Evaluate(err)) => err,
+            _ => panic!("{} should fail to roll", input),
+        };
+        assert!(matches!(eval_error("(2000)d6"), EvalError::TooManyDice(_)));
+        assert!(matches!(eval_error("2d(2000)"), EvalError::TooManySides(_)));
+        assert!(matches!(
+            eval_error("20x(100d6)"),
+            EvalError::TooManyDice(_)
+        ));
+        assert!(matches!(
+            eval_error("101x(1)"),
+            EvalError::TooManyRepeats(_)
+        ));
+        assert_eq!(vec![1; 100], roll::roll_many("100x(1d1)").unwrap());
+        // the repeats stop at the dice limit without making room for every result first
+        let limits = Limits {
+            max_dice: 10,
+            ..Limits::default()
+        };
+        let many = Equation::new_with_limits("2147483647x(1d6)", limits).unwrap();
+        assert!(matches!(
+            many.roll_many(),
+            Err(InvalidExpressionError::Evaluate(EvalError::TooManyDice(_)))
+        ));
+    }
+    #[test]
+    fn deeply_nested() {
+        // rounding functions, repeat groups and conditionals nested far deeper than the call stack could go
+        let floors = format!("{}1d6/2{}", "floor(".repeat(30000), ")".repeat(30000));
+        let q = Equation::new(&floors).unwrap();
+        assert!((0..=3).contains(&q.roll().unwrap()));
+        assert_eq!(1.0, q.average_f64().unwrap());
+        assert_eq!((0, 3), q.range().unwrap());
+        let sums = format!("{}1d6/2{}", "sum(1x(".repeat(30000), "))".repeat(30000));
+        let q = Equation::new(&sums).unwrap();
+        assert!((0..=3).contains(&q.roll().unwrap()));
+        assert_eq!(1.75, q.average_f64().unwrap());
+        assert_eq!((0.5, 3.0), q.range_f64().unwrap());
+        let conditional = Equation::new(&format!("{}0", "1d6>3 ? 1 : ".repeat(3000))).unwrap();
+        assert_eq!((0, 1), conditional.range().unwrap());
+    }
+    #[test]
+    fn limits_explosions() {
+        let limits = Limits {
+            max_explosions: 3,
+            max_rerolls: 2,
+            ..Limits::default()
+        };
+        let exploding = Equation::new_with_limits("1d1!", limits).unwrap();
+        assert_eq!(4, exploding.roll().unwrap());
+        assert_eq!(4, exploding.high().unwrap());
+        let rerolling = Equation::new_with_limits("1d6r<7", limits);
+        assert!(rerolling.is_err());
+        let rerolling = Equation::new_with_limits("1d2r1", limits).unwrap();
+        for _n in 0..20 {
+            match rerolling.roll() {
+                Ok(value) => assert_eq!(2, value),
+                Err(InvalidExpressionError::Evaluate(EvalError::RerollLimit(_))) => {}
+                Err(err) => panic!("{}", err),
+            }
+        }
+        let long = format!("{}1", "1d6+".repeat(150));
+        assert!(Equation::new(&long).is_ok());
+        match roll::roll(&long) {
+            Err(err) => assert_eq!("Error: The expression is too long", err.to_string()),
+            Ok(_) => panic!(),
+        }
+    }
+    #[test]
+    fn limits_outcomes() {
+        let limits = Limits {
+            max_outcomes: 1000,
+            ..Limits::default()
+        };
+        let kept = Equation::new_with_limits("4d6!kh3", limits).unwrap();
+        assert_eq!(15, kept.average().unwrap());
+        // half of the faces explode, so each of the 100 faces is followed through about 50 explosions
+        let slow = Equation::new_with_limits("2d100!>50kh1", limits).unwrap();
+        assert!(matches!(
+            slow.average(),
+            Err(InvalidExpressionError::Evaluate(
+                EvalError::TooManyOutcomes(_)
+            ))
+        ));
+        assert!(slow.roll().is_ok());
+        assert!(slow.range().is_ok());
+        // the statistics of dice with huge numbers of sides are worked out without going through every face
+        let huge = Equation::new("1d2000000000!").unwrap();
+        assert_eq!((1, 202_000_000_000), huge.range_i64().unwrap());
+        assert_eq!(1_000_000_001, huge.average_i64().unwrap());
+        assert_eq!(
+            (-3, 303),
+            Equation::new("3d2000000000>5f<3!")
+                .unwrap()
+                .range()
+                .unwrap()
+        );
+        assert!(matches!(
+            Equation::new("2d2000000000!kh1").unwrap().average(),
+            Err(InvalidExpressionError::Evaluate(
+                EvalError::TooManyOutcomes(_)
+            ))
+        ));
+        let pool = Equation::new_with_limits("10d1000!!>1>=500", limits).unwrap();
+        assert!(pool.high().is_err());
+        assert!(Equation::new("1d1000!>1kh1")
+            .unwrap()
+            .with_explosion_limit(1)
+            .average()
+            .is_ok());
+    }
+    #[test]
+    fn ast() {
+        let ast = Equation::new("3 + 2(1d6kh1 - 1)").unwrap().ast();
+        let Expr::Binary { op, lhs, rhs } = ast else {
+            panic!()
+        };
+        assert_eq!(BinaryOp::Add, op);
+        assert_eq!(Expr::Number(3), *lhs);
+        let Expr::Binary { op, rhs, .. } = *rhs else {
+            panic!()
+        };
+        assert_eq!(BinaryOp::Multiply, op);
+        let Expr::Group(inner) = *rhs else { panic!() };
+        let Expr::Binary { lhs, .. } = *inner else {
+            panic!()
+        };
+        let Expr::Dice(dice) = *lhs else { panic!() };
+        assert_eq!(Sides::Number(6), dice.sides);
+        assert!(dice.keep.is_some());
+        for (input, shown) in [
+            ("3 + 1d6(4+3^2)", "3 + 1d6*(4+3^2)"),
+            ("((1+2))", "((1+2))"),
+            ("max(1,1d6 - 2)", "max(1, 1d6-2)"),
+            ("floor(2d6/2)", "floor(2d6/2)"),
+            ("2d@sides", "2d(@sides)"),
+            ("d%!!>90", "1d100!!>90"),
+            ("10d10>=8f1", "10d10>=8f1"),
+            ("1d20+5 >= 15 ? 2d6+3 : 0", "1d20 + 5 >= 15 ? 2d6 + 3 : 0"),
+            ("(2d6) > 7", "(2d6) > 7"),
+            ("6#(4d6kh3)", "6x(4d6kh3)"),
+            ("(1d6+2)[fire] + 1d4 [cold]", "(1d6+2)[fire] + 1d4[cold]"),
+            ("2^3^2", "2^3^2"),
+            ("(2^3)^2", "(2^3)^2"),
+        ] {
+            let ast = Equation::new(input).unwrap().ast();
+            assert_eq!(shown, ast.to_string(), "{}", input);
+            assert_eq!(ast, Equation::new(shown).unwrap().ast(), "{}", input);
+        }
+    }
+    #[test]
+    fn ast_visitor_and_fold() {
+        struct Variables(Vec<String>);
+        impl Visitor for Variables {
+            fn visit_expr(&mut self, expr: &Expr) {
+                if let Expr::Variable(name) = expr {
+                    self.0.push(name.clone());
+                }
+                dice_forge::ast::walk_expr(self, expr);
+            }
+        }
+        let mut variables = Variables(Vec::new());
+        let equation = Equation::new("(@count)d(@sides) + max(@a, 6x(@b))").unwrap();
+        variables.visit_expr(&equation.ast());
+        assert_eq!(vec!["count", "sides", "a", "b"], variables.0);
+        // doubles every number of dice written in the equation
+        struct Double;
+        impl Fold for Double {
+            fn fold_expr(&mut self, expr: Expr) -> Expr {
+                match expr {
+                    Expr::Dice(mut dice) => {
+                        if let Expr::Number(count) = *dice.count {
+                            dice.count = Box::new(Expr::Number(count * 2));
+                        }
+                        Expr::Dice(dice)
+                    }
+                    expr => fold_children(self, expr),
+                }
+            }
+        }
+        let doubled = Double.fold_expr(Equation::new("1d6 + (2d4)*2").unwrap().ast());
+        assert_eq!("2d6 + (4d4)*2", doubled.to_string());
+        let equation = Equation::from_ast(&doubled).unwrap();
+        assert_eq!((10, 44), equation.range().unwrap());
+        let broken = Expr::Binary {
+            op: BinaryOp::Divide,
+            lhs: Box::new(Expr::Number(1)),
+            rhs: Box::new(Expr::Number(0)),
+        };
+        match Equation::from_ast(&broken) {
+            Err(e) => assert_eq!("Error: Attempted to divide by 0\n1/0\n ^", e.to_string()),
+            Ok(_) => panic!(),
+        }
+        // the tree is always shown in the standard notation, whatever dialect it was read from
+        let limits = Limits {
+            max_explosions: 1,
+            ..Limits::untrusted()
+        };
+        let german = Equation::new_with_dialect("2W6!", Dialect::german(), limits).unwrap();
+        let rebuilt = Equation::from_ast_with_limits(&german.ast(), limits).unwrap();
+        assert_eq!(german, rebuilt);
+        assert_eq!(24, rebuilt.high().unwrap());
+        assert!(
+            Equation::from_ast_with_limits(&Equation::new("2000d6").unwrap().ast(), limits)
+                .is_err()
+        );
+    }
+    #[test]
+    fn display() {
+        let equation = Equation::new("3 + 1d6(4+3^2)").unwrap();
+        assert_eq!("3 + 1d6*(4+3^2)", equation.to_string());
+        for input in [
+            "3 + 1d6(4+3^2)",
+            "((1d20)) + (5)",
+            "2^3^2",
+            "(2^3)^2",
+            "4d6kh3 + 2d20kl1!>19r<2",
+            "(1d4)d(2d3*4) - -3",
+            "1d20+5 >= 15 ? 2d6+3 : 0",
+            "max(1, 1d6-2) + clamp(2d6, 3, 10)",
+            "6x(4d6kh3)",
+            "1d8[slashing] + 2d6[fire] + @bonus",
+            "10d10>=8f1 + 4dF + d% + d{1,1,2,3,5,8}",
+            "(2)d6 + ((3))d6kh1",
+            "(0)d6",
+        ] {
+            let equation = Equation::new(input).unwrap();
+            assert_eq!(
+                equation,
+                Equation::new(&equation.to_string()).unwrap(),
+                "{}",
+                input
+            );
+        }
+        // deeply nested equations are shown without running out of stack
+        let sum = Equation::new(&format!("1{}", "+1".repeat(10000))).unwrap();
+        assert_eq!(format!("1{}", " + 1".repeat(10000)), sum.to_string());
+        let input = format!("{}1{}", "(1+".repeat(5000), ")".repeat(5000));
+        let nested = Equation::new(&input).unwrap();
+        assert_eq!(input, nested.ast().to_string());
+        let conditional = Equation::new(&format!("{}0", "1d6>3 ? 1 : ".repeat(3000))).unwrap();
+        assert_eq!(
+            conditional,
+            Equation::new(&conditional.to_string()).unwrap()
+        );
+    }
+    #[test]
+    fn display_round_trips() {
+        use rand::rngs::StdRng;
+        use rand::{Rng, SeedableRng};
+        // builds an equation out of the parts of the notation, nested up to `depth` deep
+        fn generate(rng: &mut StdRng, depth: u32) -> String {
+            let leaves = [
+                "0",
+                "3",
+                "12",
+                "@x",
+                "1d6",
+                "4d6kh3",
+                "2d20dl1!",
+                "3d8r1",
+                "10d10>=8f1",
+                "4dF",
+                "d%",
+                "d{-1,0,2}",
+                "(2)d6",
+                "(0)d4",
+                "((3))d6!",
+            ];
+            let ops = ["+", "-", "*", "/", "^", " == ", " < ", " && ", " || "];
+            let leaf = leaves[rng.gen_range(0..leaves.len())];
+            let op = ops[rng.gen_range(0..ops.len())];
+            let choice = match depth {
+                0 => 0,
+                _ => rng.gen_range(0..12),
+            };
+            let mut inner = || generate(rng, depth - 1);
+            match choice {
+                0..=2 => leaf.to_string(),
+                3 => format!("({})", inner()),
+                4 => format!("{}{}{}", inner(), op, inner()),
+                5 => format!("-{}", inner()),
+                6 => format!("({})d({})", inner(), inner()),
+                7 => format!("{} ? {} : {}", inner(), inner(), inner()),
+                8 => format!("max({}, {})", inner(), inner()),
+                9 => format!("floor({})", inner()),
+                10 => format!("({})x({})", inner(), inner()),
+                _ => format!("{}[label]", inner()),
+            }
+        }
+        let mut rng = StdRng::seed_from_u64(22);
+        let mut compiled = 0;
+        for _ in 0..2000 {
+            let input = generate(&mut rng, 4);
+            let Ok(equation) = Equation::new(&input) else {
+                continue;
+            };
+            compiled += 1;
+            let shown = equation.to_string();
+            assert_eq!(
+                Ok(&equation),
+                Equation::new(&shown).as_ref(),
+                "{} shown as {}",
+                input,
+                shown
+            );
+            let ast = equation.ast().without_groups();
+            assert_eq!(
+                Ok(&equation),
+                Equation::from_ast(&ast).as_ref(),
+                "{}",
+                input
+            );
+        }
+        assert!(compiled > 1000, "only {} equations compiled", compiled);
+    }
+    #[test]
+    fn standard_traits() {
+        let equation: Equation = "1d6 * 2".parse().unwrap();
+        assert_eq!(equation, Equation::new("1d6(2)").unwrap());
+        assert_eq!(equation, Equation::try_from("(1d6)*2").unwrap());
+        assert_ne!(equation, Equation::new("2 * 1d6").unwrap());
+        assert_ne!(equation, equation.clone().with_explosion_limit(5));
+        assert!(matches!(
+            "1d6 +".parse::<Equation>(),
+            Err(InvalidExpressionError::Parse(_))
+        ));
+        let mut rolls = HashMap::new();
+        rolls.insert(equation.clone(), 1);
+        rolls.insert(Equation::new("1d6*2").unwrap(), 2);
+        assert_eq!(1, rolls.len());
+        assert_eq!(Some(&2), rolls.get(&equation));
+        assert!(format!("{:?}", equation).contains("Equation"));
+    }
+    #[test]
+    fn simplify() {
+        let equation = Equation::new("1d20+10/2^2+3").unwrap();
+        assert_eq!(equation, Equation::new("1d20+10/4+3").unwrap());
+        assert_eq!("1d20 + 10/2^2 + 3", equation.to_string());
+        assert_eq!((6, 25), equation.range().unwrap());
+        assert_eq!(
+            Equation::new("1d20 + max(2*3, 4) + (1 > 0)").unwrap(),
+            Equation::new("1d20 + 6 + 1").unwrap()
+        );
+        assert_eq!(
+            Equation::new("abs(5) + 1d6").unwrap(),
+            Equation::new("5 + 1d6").unwrap()
+        );
+        assert_eq!(3, Equation::new("floor(7/2)").unwrap().roll().unwrap());
+        assert_eq!(4, Equation::new("ceil(7/2)").unwrap().roll().unwrap());
+        assert_eq!(
+            Equation::new("2d6+4d6").unwrap(),
+            Equation::new("6d6").unwrap()
+        );
+        // merging would change the rounded averages
+        let separate = Equation::new("1d6+1d6").unwrap();
+        assert_ne!(separate, Equation::new("2d6").unwrap());
+        assert_eq!(6, separate.average().unwrap());
+        // folding never changes the answer in another number mode
+        assert_eq!(3.5, Equation::new("7/2").unwrap().roll_f64().unwrap());
+        assert_eq!(
+            Err(()),
+            Equation::new("2^31").unwrap().roll().map_err(|_| ())
+        );
+        assert_eq!(
+            2_147_483_648,
+            Equation::new("2^31").unwrap().roll_i64().unwrap()
+        );
+    }
+    #[test]
+    fn constant_divide_by_zero() {
+        match Equation::new("1d6 / (2 - 2)") {
+            Err(InvalidExpressionError::Parse(e @ ParseError::DivideByZero(_))) => assert_eq!(
+                "Error: Attempted to divide by 0\n1d6 / (2 - 2)\n    ^",
+                e.to_string()
+            ),
+            _ => panic!(),
+        }
+        assert!(matches!(
+            Equation::new("floor(1d6/0)"),
+            Err(InvalidExpressionError::Parse(ParseError::DivideByZero(_)))
+        ));
+        // only a division that always runs is an error
+        assert_eq!(0, Equation::new("0 ? 1/0 : 0").unwrap().roll().unwrap());
+        assert!(Equation::new("1d6 > 3 && 1/0").is_ok());
+    }
+    #[test]
+    fn dialects() {
+        let standard = Equation::new("3d6 * 2 - 1").unwrap();
+        for input in ["3D6 * 2 - 1", "3d6 × 2 − 1", "3D6×2−1"] {
+            assert_eq!(standard, Equation::new(input).unwrap(), "{}", input);
+        }
+        assert_eq!(3, roll::roll("6 ÷ 2").unwrap());
+        assert_eq!(
+            Equation::new("max(4dF, 2d20kh1) + 6x(1)").unwrap(),
+            Equation::new("MAX(4DF, 2D20KH1) + 6X(1)").unwrap()
+        );
+        // variable names and labels are kept as they were written
+        let labeled = Equation::new("1D8[Slashing] + @STR").unwrap();
+        assert_eq!(vec!["STR"], labeled.variables());
+        let vars = HashMap::from([("STR", 3)]);
+        let roll = labeled.roll_labeled_with_vars(&vars).unwrap();
+        assert_eq!("Slashing", roll.labels[0].0);
+        let german = Equation::new_with_dialect("3W6 + 1w4", Dialect::german(), Limits::default());
+        assert_eq!(Equation::new("3d6 + 1d4").unwrap(), german.unwrap());
+        assert!(Equation::new("3W6").is_err());
+        // an upper case `F` only stays a Fate die right after the die letter
+        assert_eq!(
+            "4dF + floor(7/2)",
+            Equation::new("4DF + FLOOR(7/2)").unwrap().to_string()
+        );
+        assert_eq!(
+            "10d10>=8f1",
+            Equation::new("10D10>=8F1").unwrap().to_string()
+        );
+        assert_eq!(
+            "2dF",
+            Equation::new_with_dialect("2WF", Dialect::german(), Limits::default())
+                .unwrap()
+                .to_string()
+        );
+    }
+    #[test]
+    fn dialect_options() {
+        let strict = Dialect {
+            case_sensitive: true,
+            unicode_operators: false,
+            ..Dialect::default()
+        };
+        let limits = Limits::default();
+        assert!(Equation::new_with_dialect("3d6*2-1", strict.clone(), limits).is_ok());
+        match Equation::new_with_dialect("3D6", strict.clone(), limits) {
+            Err(e) => assert_eq!(
+                "Error: Unexpected token 'D' found while parsing\n3D6\n ^",
+                e.to_string()
+            ),
+            Ok(_) => panic!(),
+        }
+        assert!(Equation::new_with_dialect("2 × 3", strict.clone(), limits).is_err());
+        let upper_w = Dialect {
+            die_letters: vec!['W'],
+            ..strict
+        };
+        assert!(Equation::new_with_dialect("3W6", upper_w.clone(), limits).is_ok());
+        assert!(Equation::new_with_dialect("3w6", upper_w, limits).is_err());
+        // die letters can't be letters the notation already uses
+        for letter in ['k', 'X', 'm'] {
+            let dialect = Dialect {
+                die_letters: vec![letter],
+                ..Dialect::default()
+            };
+            match Equation::new_with_dialect("1d6", dialect, limits) {
+                Err(InvalidExpressionError::Parse(ParseError::InvalidDieLetter(found, _))) => {
+                    assert_eq!(letter, found)
+                }
+                _ => panic!(),
+            }
+        }
+        // errors point at the characters as they were written
+        match Equation::new("1d6 − 3d0") {
+            Err(e) => assert_eq!(8..11, e.location().span),
+            Ok(_) => panic!(),
+        }
+        let equation = Equation::new_with_dialect("(2W6) × 2", Dialect::german(), limits).unwrap();
+        assert_eq!("(2d6)*2", equation.ast().to_string());
+        assert_eq!(equation, Equation::new(&equation.to_string()).unwrap());
+    }
 }